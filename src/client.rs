@@ -1,14 +1,20 @@
 use crate::resources::Resources;
-use crate::common::TILE_SIZE;
 use std::{env, fs, io};
 use crate::debugutils::log;
-use crate::map::{Map, TileKind};
-use crate::packet::{self, PlayerPacket, send_packet};
-use crate::player::{ActionType, Player};
-use crate::item::{Item, ItemKind, WeaponKind};
+use crate::common::TILE_SIZE;
+use crate::gamemode::{FlagInfo, GameModeKind, MatchInfo, MatchPhase, MatchResult, ScoreboardEntry, Team};
+use crate::ghost::{Ghost, GhostRecorder, PERSONAL_GHOSTS};
+use crate::map::{ItemChange, LayerKind, Map, EMPTY_TILE};
+use crate::movement::{self, Body, PLAYER_HEIGHT, PLAYER_SPEED, PLAYER_WIDTH, SPAWN_POINT};
+use crate::leaderboard::LeaderboardEntry;
+use crate::packet::{self, Packet, PlayerPacket, send_packet};
+use crate::player::{ActionType, Player, INVENTORY_SIZE};
+use crate::race::RaceTimer;
+use crate::render::{self, team_color};
+use crate::item::{ItemKind, Weapon, WeaponKind, ITEM_SIZE};
+use crate::shot::{self, Shot, ShotTrace};
 use macroquad::rand::{gen_range, srand, ChooseRandom};
-use ::rand;
-use macroquad::audio::{load_sound, play_sound};
+use macroquad::audio::play_sound;
 use macroquad::prelude::*;
 use sha2::{Digest, Sha256};
 use std::default::Default;
@@ -16,14 +22,33 @@ use std::io::{Read, Write};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
     net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex},
 };
 use std::{process, thread};
 
-use macroquad::ui::{hash, root_ui, widgets};
 
-static PLAYER_WIDTH: f32 = 50.0;
-static PLAYER_HEIGHT: f32 = 50.0;
+static TRACER_LIFETIME: f32 = 0.1;
+static LEADERBOARD_SIZE: u32 = 10;
+
+static FEED_LIFETIME: f32 = 6.0;
+
+struct Tracer {
+    trace: ShotTrace,
+    ttl: f32,
+}
+
+/// A line in the on-screen event feed (lap times, announcements...).
+struct FeedMessage {
+    text: String,
+    ttl: f32,
+}
+
+#[derive(PartialEq)]
+enum GhostSource {
+    Personal,
+    Record,
+    Off,
+}
 
 enum GameInputState {
     Chat,
@@ -48,9 +73,6 @@ pub async fn main() {
 
     println!("Loading assets...");
     let resources: Resources = Resources::load().await;
-    let player_texture =
-        Texture2D::from_file_with_format(include_bytes!("../res/player.png"), None);
-    let chat_sound = load_sound("res/chat.wav").await.unwrap();
 
     println!("Connecting to server...");
     let mut stream = match TcpStream::connect("127.0.0.1:8080") {
@@ -82,7 +104,7 @@ pub async fn main() {
             )
         }
     };
-    println!("Map fetched");
+    println!("Map fetched: {}", map.header.name);
 
     let random_name = ["Mark", "Lily", "Jake", "Ella", "Ryan", "Zoe", "Alex", "Mia", "Luke", "Emmma"]
         .choose()
@@ -99,6 +121,42 @@ pub async fn main() {
     let mut delete_message_timer = 0.0;
 
     let mut last_shot_time: Instant = Instant::now();
+    let mut reload_done_time: Option<Instant> = None;
+    let mut tracers: Vec<Tracer> = Vec::new();
+    let mut feed: Vec<FeedMessage> = Vec::new();
+    let mut race_timer = RaceTimer::default();
+    let mut leaderboard: Vec<LeaderboardEntry> = Vec::new();
+    let mut match_info: Option<MatchInfo> = None;
+    let mut match_info_received = Instant::now();
+    let mut match_result: Option<MatchResult> = None;
+    let mut flags: Vec<FlagInfo> = Vec::new();
+    let mut scoreboard: Vec<ScoreboardEntry> = Vec::new();
+
+    let mut map_hash = map.hash();
+    let mut ghost_recorder = GhostRecorder::default();
+    let mut personal_ghost = Ghost::load(PERSONAL_GHOSTS, &map_hash);
+    let mut record_ghost: Option<Ghost> = None;
+    let mut ghost_source = GhostSource::Personal;
+    let mut upload_ghosts = true;
+    // Sent after the player packet finishing the lap, so the server has timed it by then
+    let mut ghost_upload: Option<Ghost> = None;
+
+    // Packets are read on their own thread so a partially received packet never blocks a frame
+    let (packet_sender, packet_receiver) = mpsc::channel::<Packet>();
+    let mut read_stream = stream.try_clone().unwrap();
+    thread::spawn(move || loop {
+        match packet::receive_packet(&mut read_stream) {
+            Ok(packet) => {
+                if packet_sender.send(packet).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!("Receive error: {}", e);
+                break;
+            }
+        }
+    });
 
     let mut frame_counter: i128 = 0;
 
@@ -130,45 +188,86 @@ pub async fn main() {
         clear_background(GRAY);
 
         // Receive packets
-        match stream.try_clone() {
-            Ok(mut clone) => {
-                clone.set_nonblocking(true).ok();
-                match packet::receive_packet(&mut clone) {
-                    Ok(packet) => {
-                        let mut packets = player_packets.lock().unwrap();
-                        if let Some(existing) = packets.iter_mut().find(|p| p.id == packet.id) {
-                            log(frame_counter, 10, format!("Recieving packages correctly from {}", packet.id).as_str());
-                            *existing = packet;
-                        } else {
-                            println!("Received packet from {}, {:?}", packet.id, packet);
-                            packets.push(packet);
-                        }
+        while let Ok(packet) = packet_receiver.try_recv() {
+            match packet {
+                Packet::Player(packet) => {
+                    let friendly_fire = match_info.as_ref().is_none_or(|info| info.friendly_fire);
+                    handle_remote_actions(&packet, &mut player, &mut map, &mut tracers, &mut feed, friendly_fire);
+                    let mut packets = player_packets.lock().unwrap();
+                    if let Some(existing) = packets.iter_mut().find(|p| p.id == packet.id) {
+                        log(frame_counter, 10, format!("Recieving packages correctly from {}", packet.id).as_str());
+                        *existing = packet;
+                    } else {
+                        println!("Received packet from {}, {:?}", packet.id, packet);
+                        packets.push(packet);
                     }
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                    Err(e) => println!("Receive error: {}", e),
                 }
-                clone.set_nonblocking(false).ok();
-            }
-            Err(e) => println!("Stream clone error: {}", e),
-        }
-        
-
-        let packets = player_packets.lock().unwrap().clone();
-
-        for packet in packets {
-           for action in packet.actions {
-                match action {
-                    ActionType::PickUp(id) => {
-                        map.items.retain(|item| item.id != id);
-
+                Packet::Leaderboard(entries) => leaderboard = entries,
+                Packet::Ghost(ghost) => record_ghost = ghost,
+                Packet::MatchInfo(info) => {
+                    if info.team != player.team
+                        && let Some(team) = info.team
+                    {
+                        feed.push(FeedMessage { text: format!("You are on the {:?} team", team), ttl: FEED_LIFETIME });
+                    }
+                    if info.phase != MatchPhase::PostGame && match_result.take().is_some() {
+                        respawn(&mut player);
+                    }
+                    player.team = info.team;
+                    match_info = Some(info);
+                    match_info_received = Instant::now();
+                }
+                Packet::MatchEnd(result) => match_result = Some(result),
+                Packet::Flags(new_flags) => flags = new_flags,
+                Packet::Ping(sent) => request_packet(&mut stream, &Packet::Pong(sent)),
+                Packet::Scoreboard(entries) => scoreboard = entries,
+                Packet::ChangeMap(new_map) => {
+                    map = new_map;
+                    map_hash = map.hash();
+                    personal_ghost = Ghost::load(PERSONAL_GHOSTS, &map_hash);
+                    record_ghost = None;
+                    ghost_recorder = GhostRecorder::default();
+                    ghost_upload = None;
+                    race_timer = RaceTimer::default();
+                    leaderboard.clear();
+                    flags.clear();
+                    tracers.clear();
+                    player_packets.lock().unwrap().clear();
+                    player.items = vec![None; INVENTORY_SIZE];
+                    respawn(&mut player);
+                    feed.push(FeedMessage { text: format!("Map changed to {}", map.header.name), ttl: FEED_LIFETIME });
+                }
+                Packet::TileChanged(changes) => {
+                    for change in &changes {
+                        map.apply_change(change);
                     }
-                    ActionType::Shot((weapon_kind, from_x, from_y, to_x, to_y)) => {
-                        draw_line(from_x, from_y, to_x, to_y, 1.0, WHITE);
+                }
+                Packet::ItemsChanged(changes) => {
+                    for change in &changes {
+                        map.apply_item_change(change);
+                    }
+                }
+                Packet::SetPosition(x, y) => {
+                    (player.x, player.y) = (x, y);
+                    (player.vx, player.vy) = (0.0, 0.0);
+                }
+                Packet::Hurt(damage) => {
+                    player.health = player.health.saturating_sub(damage);
+                    if player.health == 0 {
+                        player.actions.push(ActionType::Died(player.id));
+                        feed.push(FeedMessage { text: "You died".to_string(), ttl: FEED_LIFETIME });
+                        respawn(&mut player);
                     }
- 
                 }
-           } 
+                Packet::ServerMessage(message) => {
+                    play_sound(&resources.chat_sound, Default::default());
+                    feed.push(FeedMessage { text: format!("[Server] {}", message), ttl: FEED_LIFETIME });
+                }
+                Packet::LeaderboardRequest(_) | Packet::GhostUpload(_) | Packet::GhostRequest | Packet::Pong(_) => {}
+            }
         }
+        
+
         // === Drawing ===
         
 
@@ -180,46 +279,65 @@ pub async fn main() {
 GRAY,
         );
 
+        // === Map and Objects Rendering ===
+        for kind in LayerKind::GAMEPLAY {
+            render::draw_layer(&map, kind, &camera, &resources, WHITE);
+        }
+
         render_player(&player, &resources).await;
-        render_players(player_packets.lock().unwrap().clone(), &resources).await;
+        let packets = player_packets.lock().unwrap().clone();
+        render_players(packets.clone(), &resources, &map, vec2(player.x, player.y)).await;
 
-        // === Map and Objects Rendering ===
-        for (y, row) in map.tiles.iter().enumerate() {
-            for (x, tile) in row.iter().enumerate() {
-                match tile.kind {
-                    TileKind::Grass => draw_rectangle(
-                        x as f32 * TILE_SIZE,
-                        y as f32 * TILE_SIZE,
-                        TILE_SIZE,
-                        TILE_SIZE,
-                        GREEN,
-                    ),
-                    TileKind::Rock => draw_rectangle(
-                        x as f32 * TILE_SIZE,
-                        y as f32 * TILE_SIZE,
-                        TILE_SIZE,
-                        TILE_SIZE,
-                        BLACK,
-                    ),
-                    TileKind::Empty => {}
-                }
-            }
+        let ghost = match ghost_source {
+            GhostSource::Personal => personal_ghost.as_ref(),
+            GhostSource::Record => record_ghost.as_ref(),
+            GhostSource::Off => None,
+        };
+        if let (Some(ghost), Some(time)) = (ghost, race_timer.elapsed()) {
+            render_ghost(ghost, time, &resources);
         }
 
+        for object in &map.objects {
+            render::draw_map_object(object);
+        }
+        for flag in &flags {
+            let (x, y) = match flag.carrier {
+                Some(id) if id == player.id => (player.x, player.y - PLAYER_HEIGHT / 2.0),
+                Some(id) => match packets.iter().find(|p| p.id == id) {
+                    Some(carrier) => (carrier.x, carrier.y - PLAYER_HEIGHT / 2.0),
+                    None => (flag.x, flag.y),
+                },
+                None => (flag.x, flag.y),
+            };
+            render::draw_flag(x, y, flag.team);
+        }
+
+        // Roofs and other overlays turn see-through while the player stands under them
+        let under_overlay = player.x >= 0.0
+            && player.y >= 0.0
+            && map
+                .layer(LayerKind::Overlay)
+                .get((player.x / TILE_SIZE) as usize, (player.y / TILE_SIZE) as usize)
+                .is_some_and(|tile| tile.id != EMPTY_TILE.id);
+        let overlay_tint = if under_overlay { Color::new(1.0, 1.0, 1.0, 0.35) } else { WHITE };
+        render::draw_layer(&map, LayerKind::Overlay, &camera, &resources, overlay_tint);
+
  
 
 
+        let mut picked_up = false;
         for item in &mut map.items {
-            let item_rect = Rect::new(item.x, item.y, 32.0, 32.0);
+            let item_rect = Rect::new(item.x, item.y, ITEM_SIZE, ITEM_SIZE);
            
 
-            if is_key_pressed(KeyCode::E) && player_rect.overlaps(&item_rect) {
-                player.items.push(Item { id: item.id, x: item.x, y: item.y, picked: false, name: item.name.clone(), texture: item.texture.clone(), texture_equipped: item.texture_equipped.clone(), kind: item.kind.clone() });
-                    player.actions.push(ActionType::PickUp(item.id));            
+            if is_key_pressed(KeyCode::E) && !picked_up && player_rect.overlaps(&item_rect) && player.pick_up(item.clone()) {
+                player.actions.push(ActionType::PickUp(item.id));
+                picked_up = true;
             }
 
+            let ItemKind::Weapon(ref weapon) = item.kind;
             draw_texture_ex(
-                &Texture2D::from_file_with_format(include_bytes!("../res/weapon_ak47.png"), None),
+                resources.weapon_texture(&weapon.weapon_kind),
                 item.x,
                 item.y,
                 WHITE,
@@ -230,111 +348,147 @@ GRAY,
         }
 
         for action in &player.actions {
-            match action {
-                ActionType::PickUp(id) => {
-                    map.items.retain(|x| x.id != *id);
-                },
-                ActionType::Shot((weapon_kind, from_x, from_y, to_x, to_y)) => {
-                    draw_line(*from_x, *from_y, *to_x, *to_y, 1.0, WHITE);
-                }
+            if let ActionType::PickUp(id) = action {
+                map.items.retain(|x| x.id != *id);
             }
         }
+
+        tracers.retain_mut(|tracer| {
+            tracer.ttl -= get_frame_time();
+            tracer.ttl > 0.0
+        });
+        for tracer in &tracers {
+            let alpha = tracer.ttl / TRACER_LIFETIME;
+            if alpha > 0.5 {
+                // Muzzle flash for the first half of the tracer's life
+                draw_circle(tracer.trace.from.x, tracer.trace.from.y, 6.0 * alpha, Color::new(1.0, 0.85, 0.3, alpha));
+            }
+            draw_line(
+                tracer.trace.from.x,
+                tracer.trace.from.y,
+                tracer.trace.to.x,
+                tracer.trace.to.y,
+                1.0,
+                Color::new(1.0, 1.0, 1.0, alpha),
+            );
+        }
             
 
 
 
+        // Movement keys held this frame, -1 to 1 on each axis
+        let mut move_input = Vec2::ZERO;
         match game_input_state {
             GameInputState::Movement => {
-                if !player.items.is_empty() {
-
-                    if let ItemKind::Weapon(ref mut weapon) = player.items[player.current_item].kind {
-                        let shot_interval = Duration::from_secs_f32(weapon.firerate);
-
-                        let can_shoot = Instant::now().duration_since(last_shot_time) >= shot_interval;
-
-                        let firing_condition = if weapon.is_auto {
-                            is_mouse_button_down(MouseButton::Left) && can_shoot
-                        } else {
-                            is_mouse_button_pressed(MouseButton::Left) && can_shoot
+                let speed_ratio = vec2(player.vx, player.vy).length() / PLAYER_SPEED;
+                let mouse_world = camera.screen_to_world(vec2(mouse_position().0, mouse_position().1));
+                player.aim = (mouse_world - vec2(player.x, player.y)).to_angle();
+                if let Some(item) = player.items[player.current_item].as_mut() {
+                    let ItemKind::Weapon(ref mut weapon) = item.kind;
+
+                    if let Some(done) = reload_done_time
+                        && Instant::now() >= done
+                    {
+                        weapon.reload();
+                        reload_done_time = None;
+                    }
+                    if is_key_pressed(KeyCode::R)
+                        && reload_done_time.is_none()
+                        && weapon.magazine < weapon.magazine_size
+                        && weapon.ammo > 0
+                    {
+                        reload_done_time = Some(Instant::now() + Duration::from_secs(weapon.reload_time as u64));
+                    }
+
+                    let shot_interval = Duration::from_secs_f32(weapon.firerate);
+
+                    let can_shoot = Instant::now().duration_since(last_shot_time) >= shot_interval
+                        && reload_done_time.is_none();
+
+                    let firing_condition = if weapon.is_auto {
+                        is_mouse_button_down(MouseButton::Left) && can_shoot
+                    } else {
+                        is_mouse_button_pressed(MouseButton::Left) && can_shoot
+                    };
+
+                    if firing_condition && weapon.magazine >= weapon.bullets_per_shot {
+                        let spread_angle = weapon.current_spread(speed_ratio);
+                        let spread = gen_range(-spread_angle, spread_angle).to_radians();
+                        weapon.add_recoil();
+
+                        last_shot_time = Instant::now();
+                        weapon.magazine -= weapon.bullets_per_shot;
+                        let muzzle = weapon.muzzle(player.x, player.y, player.aim);
+                        let shot = Shot {
+                            weapon_kind: weapon.weapon_kind.clone(),
+                            x: muzzle.x,
+                            y: muzzle.y,
+                            angle: (mouse_world - muzzle).to_angle() + spread,
                         };
-
-                        if firing_condition {
-                            if weapon.magazine >= weapon.bullets_per_shot {
-                                let mouse_world = camera.screen_to_world(vec2(mouse_position().0, mouse_position().1)); 
-                                let offset_x = weapon.shotoffset.0;
-                                let offset_y = weapon.shotoffset.1;    
-                                
-                                let spread_x = gen_range(-weapon.spread, weapon.spread);
-                                let spread_y = gen_range(-weapon.spread, weapon.spread);
-
-                                last_shot_time = Instant::now();
-                                weapon.magazine -= weapon.bullets_per_shot;
-                                draw_line(player.x + if player.dir {offset_x} else {-offset_x}, player.y + offset_y, mouse_world.x + spread_x, mouse_world.y + spread_y,1.0, WHITE);
-                                player.actions.push(ActionType::Shot((WeaponKind::Ak47, 
-                                    player.x + if player.dir {offset_x} else {-offset_x}, 
-                                    player.y + offset_y, 
-                                    mouse_world.x, 
-                                    mouse_world.y)))
-                            } else {
-                            
-                            }
-                        }
+                        tracers.push(Tracer {
+                            trace: shot.trace(&map),
+                            ttl: TRACER_LIFETIME,
+                        });
+                        play_sound(&resources.weapon_ak47_shot_sound, Default::default());
+                        player.actions.push(ActionType::Shot(shot));
                     }
-                }
 
-                if is_key_pressed(KeyCode::Key1) {
-                    player.current_item = 0;
-                }
-                if is_key_pressed(KeyCode::Key2) {
-                    player.current_item = 1;
+                    draw_crosshair(vec2(player.x, player.y), mouse_world, weapon.current_spread(speed_ratio));
                 }
-                if is_key_pressed(KeyCode::Key3) {
-                    player.current_item = 2;
-                }
-                if is_key_pressed(KeyCode::Key4) {
-                    player.current_item = 3;
-                }
-                if is_key_pressed(KeyCode::Key5) {
-                    player.current_item = 4;
-                }
-                if is_key_pressed(KeyCode::Key6) {
-                    player.current_item = 5;
-                }
-                if is_key_pressed(KeyCode::Key7) {
-                    player.current_item = 6;
+
+                let previous_item = player.current_item;
+                let slot_keys = [
+                    KeyCode::Key1,
+                    KeyCode::Key2,
+                    KeyCode::Key3,
+                    KeyCode::Key4,
+                    KeyCode::Key5,
+                    KeyCode::Key6,
+                    KeyCode::Key7,
+                    KeyCode::Key8,
+                    KeyCode::Key9,
+                    KeyCode::Key0,
+                ];
+                for (slot, key) in slot_keys.iter().enumerate().take(INVENTORY_SIZE) {
+                    if is_key_pressed(*key) {
+                        player.current_item = slot;
+                    }
                 }
-                if is_key_pressed(KeyCode::Key8) {
-                    player.current_item = 7;
+                let wheel = mouse_wheel().1;
+                if wheel > 0.0 {
+                    player.cycle_item(-1);
+                } else if wheel < 0.0 {
+                    player.cycle_item(1);
                 }
-                if is_key_pressed(KeyCode::Key9) {
-                    player.current_item = 8;
+                if is_key_pressed(KeyCode::Q)
+                    && let Some(item) = player.drop_current()
+                {
+                    player.actions.push(ActionType::Drop(item.clone()));
+                    map.items.push(item);
                 }
-                if is_key_pressed(KeyCode::Key0) {
-                    player.current_item = 9;
+                if player.current_item != previous_item || player.current_item().is_none() {
+                    reload_done_time = None;
                 }
                 if is_key_pressed(KeyCode::T) {
                     game_input_state = GameInputState::Chat;
                 }
 
                 if is_key_down(KeyCode::A) {
-                    player.vx = -5.0;
+                    move_input.x = -1.0;
                     player.dir = false;
                 } else if is_key_down(KeyCode::D) {
-                    player.vx = 5.0;
+                    move_input.x = 1.0;
                     player.dir = true;
-                } else {
-                    player.vx = 0.0;
                 }
                 if is_key_down(KeyCode::W) {
-                    player.vy = -5.0;
+                    move_input.y = -1.0;
                 } else if is_key_down(KeyCode::S) {
-                    player.vy = 5.0;
-                } else {
-                    player.vy = 0.0;
+                    move_input.y = 1.0;
                 }
 
                 if is_key_pressed(KeyCode::Escape) {
                     game_input_state = GameInputState::Menu;
+                    request_packet(&mut stream, &Packet::LeaderboardRequest(LEADERBOARD_SIZE));
                 }
             }
 
@@ -432,7 +586,7 @@ GRAY,
                 if is_key_pressed(KeyCode::Enter) {
                     player.message = pre_message.clone();
                     pre_message.clear();
-                    play_sound(&chat_sound, Default::default());
+                    play_sound(&resources.chat_sound, Default::default());
                     game_input_state = GameInputState::Movement;
                 }
 
@@ -442,10 +596,28 @@ GRAY,
                 if is_key_pressed(KeyCode::Escape) {
                     game_input_state = GameInputState::Movement;
                 }
+                let mut refresh_leaderboard = false;
+                let was_racing_record = matches!(ghost_source, GhostSource::Record);
                 egui_macroquad::ui(|egui_ctx| {
                     egui::Window::new("Momentum").show(egui_ctx, |ui| {
                         ui.label(format!("Time played: {:.2}s", time_played));
                         ui.label(format!("FPS: {}", get_fps()));
+                        if map.has_race() {
+                            ui.heading("Leaderboard");
+                            if leaderboard.is_empty() {
+                                ui.label("No laps recorded on this map yet");
+                            }
+                            for (rank, entry) in leaderboard.iter().enumerate() {
+                                ui.label(format!("{}. {} - {:.2}s", rank + 1, entry.name, entry.time));
+                            }
+                            refresh_leaderboard = ui.button("Refresh").clicked();
+
+                            ui.heading("Ghost");
+                            ui.radio_value(&mut ghost_source, GhostSource::Personal, "My best lap");
+                            ui.radio_value(&mut ghost_source, GhostSource::Record, "Record holder");
+                            ui.radio_value(&mut ghost_source, GhostSource::Off, "Off");
+                            ui.checkbox(&mut upload_ghosts, "Share my best laps");
+                        }
                         if ui.button("Return to Game").clicked() {
                             game_input_state = GameInputState::Movement;
                         }
@@ -455,41 +627,334 @@ GRAY,
                     });
                 });
                 egui_macroquad::draw();
+                if refresh_leaderboard {
+                    request_packet(&mut stream, &Packet::LeaderboardRequest(LEADERBOARD_SIZE));
+                }
+                if ghost_source == GhostSource::Record && (!was_racing_record || refresh_leaderboard) {
+                    request_packet(&mut stream, &Packet::GhostRequest);
+                }
+            }
+        }
+
+        let previous_position = vec2(player.x, player.y);
+        let mut body = Body { position: vec2(player.x, player.y), velocity: vec2(player.vx, player.vy) };
+        body.step(&map, move_input, get_frame_time());
+        (player.x, player.y) = (body.position.x, body.position.y);
+        (player.vx, player.vy) = (body.velocity.x, body.velocity.y);
+        if map.has_race() {
+            let lap = race_timer.update(&map, previous_position, vec2(player.x, player.y), get_frame_time());
+            if let Some(time) = race_timer.elapsed() {
+                ghost_recorder.record(time, player.x, player.y, player.dir);
+            }
+            if let Some(lap) = lap {
+                player.actions.push(ActionType::LapFinished(lap));
+                feed.push(FeedMessage { text: format!("You finished a lap in {:.2}s", lap), ttl: FEED_LIFETIME });
+
+                let ghost = ghost_recorder.finish(&player.name, lap, player.x, player.y, player.dir);
+                if personal_ghost.as_ref().is_none_or(|best| lap < best.time) {
+                    ghost.save(PERSONAL_GHOSTS, &map_hash);
+                    if upload_ghosts {
+                        ghost_upload = Some(ghost.clone());
+                    }
+                    personal_ghost = Some(ghost);
+                }
             }
         }
 
         // == Send Packets ==
         if will_send <= 1 {
-            let packet = PlayerPacket::from_player(&player);
+            let packet = Packet::Player(PlayerPacket::from_player(&player));
             match send_packet(&mut stream, &packet) {
                 Ok(_) => {},
                 Err(e) => {
                     eprintln!("Failed to send packet: {}", e)
                 }
             };
+            if let Some(ghost) = ghost_upload.take() {
+                request_packet(&mut stream, &Packet::GhostUpload(ghost));
+            }
             will_send = 1;
         } else {
             will_send -= 1;
         }
 
-        widgets::Window::new(hash!(), vec2(0.0, 100.0), vec2(32., 200.))
-        .movable(false)
-        .titlebar(false)
-        .ui(&mut root_ui(), |ui| {
-            for (i, item) in player.items.iter().enumerate() {
-                ui.texture(Texture2D::from_file_with_format(fs::read(item.texture.clone().expect("Expected texture").as_str()).unwrap().as_slice(), None), 32.0, 32.0);
-            }
-        });
-
-        handle_collisions(&mut player, &map);
+        for weapon_item in player.items.iter_mut().flatten() {
+            let ItemKind::Weapon(ref mut weapon) = weapon_item.kind;
+            weapon.recover(get_frame_time());
+        }
+        show_mouse(!matches!(game_input_state, GameInputState::Movement) || player.current_item().is_none());
+
+        render_inventory(&player, &resources, reload_done_time.is_some());
+        let carried_flag = flags.iter().find(|flag| flag.carrier == Some(player.id)).map(|flag| flag.team);
+        let phase_time_left = match_info
+            .as_ref()
+            .and_then(|info| info.phase_time_left)
+            .map(|left| (left as f32 - match_info_received.elapsed().as_secs_f32()).max(0.0));
+        render_hud(&mut feed, race_timer.elapsed(), match_info.as_ref(), phase_time_left, carried_flag);
+        if let Some(result) = &match_result {
+            render_match_end(result);
+        } else if is_key_down(KeyCode::Tab) {
+            render_scoreboard(&scoreboard, player.id);
+        }
         time_played += get_frame_time();
         frame_counter += 1;
         next_frame().await;
     }
 }
 
-async fn render_players(player_packets: Vec<PlayerPacket>, resources: &Resources) {
+fn handle_remote_actions(
+    packet: &PlayerPacket,
+    player: &mut Player,
+    map: &mut Map,
+    tracers: &mut Vec<Tracer>,
+    feed: &mut Vec<FeedMessage>,
+    friendly_fire: bool,
+) {
+    for action in &packet.actions {
+        match action {
+            ActionType::Died(killer) => {
+                let text = if *killer == player.id {
+                    format!("You killed {}", packet.name)
+                } else {
+                    format!("{} died", packet.name)
+                };
+                feed.push(FeedMessage { text, ttl: FEED_LIFETIME });
+            }
+            ActionType::LapFinished(lap) => {
+                feed.push(FeedMessage {
+                    text: format!("{} finished a lap in {:.2}s", packet.name, lap),
+                    ttl: FEED_LIFETIME,
+                });
+            }
+            ActionType::PickUp(id) => {
+                map.apply_item_change(&ItemChange::PickedUp(*id));
+            }
+            ActionType::Drop(item) => {
+                map.apply_item_change(&ItemChange::Dropped(item.clone()));
+            }
+            ActionType::Respawned => {}
+            ActionType::Shot(shot) => {
+                let weapon = Weapon::from_kind(&shot.weapon_kind);
+                let trace = shot.trace(map);
+
+                let player_rect = movement::body_rect(vec2(player.x, player.y));
+                let teammate = packet.team.is_some() && packet.team == player.team;
+                if let Some(distance) = shot::hit_distance(&trace, &player_rect)
+                    && (friendly_fire || !teammate)
+                {
+                    player.health = player.health.saturating_sub(weapon.damage_at(distance));
+                    if player.health == 0 {
+                        player.actions.push(ActionType::Died(packet.id));
+                        feed.push(FeedMessage { text: format!("{} killed you", packet.name), ttl: FEED_LIFETIME });
+                        respawn(player);
+                    }
+                }
+
+                tracers.push(Tracer { trace, ttl: TRACER_LIFETIME });
+            }
+        }
+    }
+}
+
+fn respawn(player: &mut Player) {
+    player.health = 100;
+    player.x = SPAWN_POINT.x;
+    player.y = SPAWN_POINT.y;
+    player.vx = 0.0;
+    player.vy = 0.0;
+    player.actions.push(ActionType::Respawned);
+}
+
+/// Draws a crosshair at `target` whose gap matches the current spread cone at that distance.
+fn draw_crosshair(origin: Vec2, target: Vec2, spread: f32) {
+    const LENGTH: f32 = 6.0;
+
+    let gap = (target.distance(origin) * spread.to_radians().tan()).max(2.0);
+    let color = Color::new(1.0, 1.0, 1.0, 0.8);
+    draw_line(target.x - gap - LENGTH, target.y, target.x - gap, target.y, 1.0, color);
+    draw_line(target.x + gap, target.y, target.x + gap + LENGTH, target.y, 1.0, color);
+    draw_line(target.x, target.y - gap - LENGTH, target.x, target.y - gap, 1.0, color);
+    draw_line(target.x, target.y + gap, target.x, target.y + gap + LENGTH, 1.0, color);
+    draw_circle(target.x, target.y, 0.5, color);
+}
+
+fn request_packet(stream: &mut TcpStream, packet: &Packet) {
+    if let Err(e) = send_packet(stream, packet) {
+        eprintln!("Failed to send packet: {}", e);
+    }
+}
+
+fn render_match_end(result: &MatchResult) {
+    set_default_camera();
+    let width = 420.0;
+    let height = 90.0 + result.scores.len() as f32 * 24.0;
+    let x = screen_width() / 2.0 - width / 2.0;
+    let y = screen_height() / 2.0 - height / 2.0;
+
+    draw_rectangle(x, y, width, height, Color::new(0.0, 0.0, 0.0, 0.75));
+    let title = match &result.winner {
+        Some(winner) => format!("{} wins!", winner),
+        None => "Draw!".to_string(),
+    };
+    draw_text(&title, x + 16.0, y + 36.0, 32.0, YELLOW);
+    draw_text("Player", x + 16.0, y + 66.0, 20.0, GRAY);
+    draw_text("K / D", x + 250.0, y + 66.0, 20.0, GRAY);
+    draw_text("Score", x + 340.0, y + 66.0, 20.0, GRAY);
+    for (i, score) in result.scores.iter().enumerate() {
+        let row_y = y + 90.0 + i as f32 * 24.0;
+        let color = if score.team.is_some() { team_color(score.team) } else { WHITE };
+        draw_text(&score.name, x + 16.0, row_y, 20.0, color);
+        draw_text(&format!("{} / {}", score.kills, score.deaths), x + 250.0, row_y, 20.0, WHITE);
+        draw_text(&score.score.to_string(), x + 340.0, row_y, 20.0, WHITE);
+    }
+}
+
+fn render_scoreboard(scoreboard: &[ScoreboardEntry], own_id: u64) {
+    set_default_camera();
+    let width = 520.0;
+    let height = 60.0 + scoreboard.len() as f32 * 24.0;
+    let x = screen_width() / 2.0 - width / 2.0;
+    let y = screen_height() / 2.0 - height / 2.0;
+
+    draw_rectangle(x, y, width, height, Color::new(0.0, 0.0, 0.0, 0.75));
+    draw_text("Player", x + 16.0, y + 30.0, 20.0, GRAY);
+    draw_text("Team", x + 200.0, y + 30.0, 20.0, GRAY);
+    draw_text("K / D", x + 280.0, y + 30.0, 20.0, GRAY);
+    draw_text("Score", x + 370.0, y + 30.0, 20.0, GRAY);
+    draw_text("Ping", x + 450.0, y + 30.0, 20.0, GRAY);
+    for (i, entry) in scoreboard.iter().enumerate() {
+        let row_y = y + 56.0 + i as f32 * 24.0;
+        let score = &entry.player;
+        let color = if score.id == own_id { YELLOW } else { WHITE };
+        let team = score.team.map(|team| format!("{:?}", team)).unwrap_or_else(|| "-".to_string());
+        let ping = entry.ping.map(|ping| ping.to_string()).unwrap_or_else(|| "?".to_string());
+        draw_text(&score.name, x + 16.0, row_y, 20.0, color);
+        draw_text(&team, x + 200.0, row_y, 20.0, if score.team.is_some() { team_color(score.team) } else { WHITE });
+        draw_text(&format!("{} / {}", score.kills, score.deaths), x + 280.0, row_y, 20.0, color);
+        draw_text(&score.score.to_string(), x + 370.0, row_y, 20.0, color);
+        draw_text(&ping, x + 450.0, row_y, 20.0, color);
+    }
+}
+
+fn render_hud(
+    feed: &mut Vec<FeedMessage>,
+    race_time: Option<f32>,
+    match_info: Option<&MatchInfo>,
+    phase_time_left: Option<f32>,
+    carried_flag: Option<Team>,
+) {
+    set_default_camera();
+
+    if let Some(info) = match_info {
+        let mode = match info.mode {
+            GameModeKind::FreeForAll => "Deathmatch",
+            GameModeKind::TeamDeathmatch => "Team deathmatch",
+            GameModeKind::CaptureTheFlag => "Capture the flag",
+        };
+        let text = match info.team {
+            Some(team) => format!("{} - {:?} team", mode, team),
+            None => mode.to_string(),
+        };
+        let size = measure_text(&text, None, 22, 1.0);
+        draw_text(&text, screen_width() - size.width - 10.0, 24.0, 22.0, team_color(info.team));
+
+        let left = phase_time_left.map(|left| left.ceil() as u32).unwrap_or(0);
+        let status = match info.phase {
+            MatchPhase::Warmup => Some(format!("Warmup - waiting for {} players", info.min_players)),
+            MatchPhase::Countdown => Some(format!("Match starts in {}", left)),
+            MatchPhase::Live if phase_time_left.is_some() => Some(format!("{}:{:02}", left / 60, left % 60)),
+            MatchPhase::Live | MatchPhase::PostGame => None,
+        };
+        if let Some(status) = status {
+            let size = measure_text(&status, None, 22, 1.0);
+            draw_text(&status, screen_width() - size.width - 10.0, 48.0, 22.0, WHITE);
+        }
+    }
+
+    feed.retain_mut(|message| {
+        message.ttl -= get_frame_time();
+        message.ttl > 0.0
+    });
+    for (i, message) in feed.iter().enumerate() {
+        draw_text(&message.text, 10.0, 24.0 + i as f32 * 22.0, 22.0, WHITE);
+    }
+
+    if let Some(team) = carried_flag {
+        let text = format!("You have the {:?} flag! Bring it to your base", team);
+        let size = measure_text(&text, None, 26, 1.0);
+        draw_text(&text, screen_width() / 2.0 - size.width / 2.0, 76.0, 26.0, team_color(Some(team)));
+    }
+
+    if let Some(time) = race_time {
+        let text = format!("Lap: {:.2}s", time);
+        let size = measure_text(&text, None, 32, 1.0);
+        draw_text(&text, screen_width() / 2.0 - size.width / 2.0, 40.0, 32.0, YELLOW);
+    }
+}
+
+fn render_inventory(player: &Player, resources: &Resources, reloading: bool) {
+    const SLOT_SIZE: f32 = 40.0;
+
+    set_default_camera();
+    let start_x = screen_width() / 2.0 - SLOT_SIZE * player.items.len() as f32 / 2.0;
+    let y = screen_height() - SLOT_SIZE - 10.0;
+
+    for (slot, item) in player.items.iter().enumerate() {
+        let x = start_x + slot as f32 * SLOT_SIZE;
+        draw_rectangle(x, y, SLOT_SIZE, SLOT_SIZE, Color::new(0.0, 0.0, 0.0, 0.4));
+        if let Some(item) = item {
+            let ItemKind::Weapon(ref weapon) = item.kind;
+            draw_texture_ex(
+                resources.weapon_texture(&weapon.weapon_kind),
+                x + 4.0,
+                y + 4.0,
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(vec2(SLOT_SIZE - 8.0, SLOT_SIZE - 8.0)),
+                    ..Default::default()
+                },
+            );
+        }
+        let (thickness, color) = if slot == player.current_item { (3.0, YELLOW) } else { (1.0, WHITE) };
+        draw_rectangle_lines(x, y, SLOT_SIZE, SLOT_SIZE, thickness, color);
+        draw_text(&((slot + 1) % 10).to_string(), x + 3.0, y + 12.0, 14.0, WHITE);
+    }
+
+    if let Some(item) = player.current_item() {
+        let ItemKind::Weapon(ref weapon) = item.kind;
+        let ammo_text = if reloading {
+            "Reloading...".to_string()
+        } else {
+            format!("{} / {}", weapon.magazine, weapon.ammo)
+        };
+        draw_text(&ammo_text, start_x, y - 8.0, 24.0, WHITE);
+    }
+}
+
+fn render_ghost(ghost: &Ghost, time: f32, resources: &Resources) {
+    let Some((x, y, dir)) = ghost.position_at(time) else {
+        return;
+    };
+    draw_text(&format!("{} (ghost)", ghost.name), x - 40.0, y - 50.0, 20.0, Color::new(0.0, 0.0, 0.0, 0.4));
+    draw_texture_ex(
+        &resources.player_texture,
+        x - PLAYER_WIDTH / 2.0,
+        y - PLAYER_HEIGHT / 2.0,
+        Color::new(1.0, 1.0, 1.0, 0.4),
+        DrawTextureParams {
+            flip_x: dir,
+            dest_size: Some(vec2(PLAYER_WIDTH, PLAYER_HEIGHT)),
+            ..Default::default()
+        },
+    );
+}
+
+/// Draws the other players, except those hidden from `viewer` by tiles that block sight.
+async fn render_players(player_packets: Vec<PlayerPacket>, resources: &Resources, map: &Map, viewer: Vec2) {
     for player_packet in player_packets {
+        if !shot::line_of_sight(map, viewer, vec2(player_packet.x, player_packet.y)) {
+            continue;
+        }
         let player = Player::from_player_packet(&player_packet);
         render_player(&player, resources).await;
     }
@@ -501,7 +966,7 @@ async fn render_player(player: &Player, resources: &Resources) {
         player.x - 40.0,
         player.y - 50.0,
         20.0,
-        BLACK,
+        team_color(player.team),
     );
 
     if player.message.chars().next().is_some() {
@@ -526,8 +991,9 @@ async fn render_player(player: &Player, resources: &Resources) {
     );
 
 
-    if !player.items.is_empty() {
-        draw_texture_ex(match player.items[player.current_item].kind {
+    if let Some(item) = player.current_item() {
+        let (rotation, facing_right) = Weapon::held_rotation(player.aim);
+        draw_texture_ex(match item.kind {
             ItemKind::Weapon(ref weapon) => {
                 match weapon.weapon_kind {
                     WeaponKind::Ak47 => &resources.weapon_ak47_texture_picked,
@@ -539,97 +1005,11 @@ async fn render_player(player: &Player, resources: &Resources) {
         player.y - PLAYER_HEIGHT / 2.0,
         WHITE,
         DrawTextureParams {
-            flip_x: player.dir,
+            flip_x: facing_right,
+            rotation,
             dest_size: Some(vec2(PLAYER_WIDTH, PLAYER_HEIGHT)),
             ..Default::default()
         });
     }
 }
 
-fn handle_collisions(player: &mut Player, map: &Map) {
-    const TILE_SIZE: f32 = 32.0;
-
-    let half_width = PLAYER_WIDTH / 2.0;
-    let half_height = PLAYER_HEIGHT / 2.0;
-
-    // --- Horizontal movement ---
-    player.x += player.vx;
-
-    let left_tile = ((player.x - half_width) / TILE_SIZE).floor() as isize;
-    let right_tile = ((player.x + half_width) / TILE_SIZE).ceil() as isize;
-    let top_tile = ((player.y - half_height) / TILE_SIZE).floor() as isize;
-    let bottom_tile = ((player.y + half_height) / TILE_SIZE).ceil() as isize;
-
-    for ty in top_tile..bottom_tile {
-        for tx in left_tile..right_tile {
-            if let Some(tile) = map.get_tile(tx as usize, ty as usize) {
-                if tile.collision {
-                    let tile_rect = Rect::new(
-                        tx as f32 * TILE_SIZE,
-                        ty as f32 * TILE_SIZE,
-                        TILE_SIZE,
-                        TILE_SIZE,
-                    );
-                    let player_rect = Rect::new(
-                        player.x - half_width,
-                        player.y - half_height,
-                        PLAYER_WIDTH,
-                        PLAYER_HEIGHT,
-                    );
-
-                    if player_rect.overlaps(&tile_rect) {
-                        if player.vx > 0.0 {
-                            // Moving right: push player back to left of tile
-                            player.x = tile_rect.x - half_width;
-                        } else if player.vx < 0.0 {
-                            // Moving left: push player to right of tile
-                            player.x = tile_rect.x + TILE_SIZE + half_width;
-                        }
-                        player.vx = 0.0;
-                    }
-                }
-            }
-        }
-    }
-
-    // --- Vertical movement ---
-    player.y += player.vy;
-
-    let left_tile = ((player.x - half_width) / TILE_SIZE).floor() as isize;
-    let right_tile = ((player.x + half_width) / TILE_SIZE).ceil() as isize;
-    let top_tile = ((player.y - half_height) / TILE_SIZE).floor() as isize;
-    let bottom_tile = ((player.y + half_height) / TILE_SIZE).ceil() as isize;
-
-    for ty in top_tile..bottom_tile {
-        for tx in left_tile..right_tile {
-            if let Some(tile) = map.get_tile(tx as usize, ty as usize) {
-                if tile.collision {
-                    let tile_rect = Rect::new(
-                        tx as f32 * TILE_SIZE,
-                        ty as f32 * TILE_SIZE,
-                        TILE_SIZE,
-                        TILE_SIZE,
-                    );
-                    let player_rect = Rect::new(
-                        player.x - half_width,
-                        player.y - half_height,
-                        PLAYER_WIDTH,
-                        PLAYER_HEIGHT,
-                    );
-
-                    if player_rect.overlaps(&tile_rect) {
-                        if player.vy > 0.0 {
-                            // Moving down: push player back up
-                            player.y = tile_rect.y - half_height;
-                        } else if player.vy < 0.0 {
-                            // Moving up: push player down
-                            player.y = tile_rect.y + TILE_SIZE + half_height;
-                        }
-                        player.vy = 0.0;
-                    }
-                }
-            }
-        }
-    }
-}
-
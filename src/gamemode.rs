@@ -0,0 +1,838 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use bincode::{Decode, Encode};
+use macroquad::math::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::map::{Map, ObjectKind};
+
+/// Distance at which a player touches a flag or a flag base.
+const FLAG_TOUCH_RADIUS: f32 = 40.0;
+/// Seconds a dropped flag stays on the ground before it returns to its base.
+const FLAG_RETURN_TIME: u64 = 30;
+/// Score a player gets for capturing the enemy flag.
+const CAPTURE_SCORE: i32 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Encode, Decode, Debug, PartialEq)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Encode, Decode, Debug, PartialEq)]
+pub enum GameModeKind {
+    FreeForAll,
+    TeamDeathmatch,
+    CaptureTheFlag,
+}
+
+/// Where a match is in its lifecycle.
+#[derive(Clone, Copy, Encode, Decode, Debug, PartialEq)]
+pub enum MatchPhase {
+    /// Waiting for enough players. Kills and captures don't count.
+    Warmup,
+    /// Enough players are in, the match goes live when the countdown runs out.
+    Countdown,
+    Live,
+    /// The match is over and the end screen is up until the next map is loaded.
+    PostGame,
+}
+
+/// Rules of a match, read by the server from `server.json`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MatchSettings {
+    pub mode: GameModeKind,
+    /// Score at which a player (or team) wins, captures in capture the flag.
+    /// 0 disables the limit.
+    pub frag_limit: u32,
+    /// Match length in seconds. 0 disables the limit.
+    pub time_limit: u32,
+    pub friendly_fire: bool,
+    /// Players needed to leave warmup.
+    pub min_players: u32,
+    /// Seconds between enough players joining and the match going live.
+    pub countdown: u32,
+    /// Seconds the end screen stays up before the next map.
+    pub post_game_time: u32,
+}
+
+impl Default for MatchSettings {
+    fn default() -> Self {
+        Self {
+            mode: GameModeKind::FreeForAll,
+            frag_limit: 20,
+            time_limit: 600,
+            friendly_fire: false,
+            min_players: 2,
+            countdown: 10,
+            post_game_time: 10,
+        }
+    }
+}
+
+/// What a client needs to know about the running match.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct MatchInfo {
+    pub mode: GameModeKind,
+    pub frag_limit: u32,
+    pub time_limit: u32,
+    pub friendly_fire: bool,
+    /// Team of the player receiving this, if the mode has teams.
+    pub team: Option<Team>,
+    pub phase: MatchPhase,
+    /// Seconds until the current phase ends, if it has a time limit.
+    pub phase_time_left: Option<u32>,
+    pub min_players: u32,
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct PlayerScore {
+    pub id: u64,
+    pub name: String,
+    pub team: Option<Team>,
+    pub kills: u32,
+    pub deaths: u32,
+    pub score: i32,
+}
+
+/// A row of the scoreboard shown while holding Tab.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct ScoreboardEntry {
+    pub player: PlayerScore,
+    /// Round trip time in milliseconds, unknown until the player answered a ping.
+    pub ping: Option<u32>,
+}
+
+/// Where a flag is, as sent to clients.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct FlagInfo {
+    pub team: Team,
+    pub x: f32,
+    pub y: f32,
+    /// Player carrying the flag, drawn on them instead of at (x, y).
+    pub carrier: Option<u64>,
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct MatchResult {
+    /// Name of the winning player or team, `None` on a draw.
+    pub winner: Option<String>,
+    pub scores: Vec<PlayerScore>,
+}
+
+pub trait GameMode: Send {
+    fn kind(&self) -> GameModeKind;
+
+    /// Team for a player about to join `players`.
+    fn assign_team(&self, players: &[PlayerScore]) -> Option<Team>;
+
+    /// Updates scores after `killer` killed `victim` (killer == victim for suicides).
+    /// Returns announcements for everyone.
+    fn on_kill(&mut self, players: &mut [PlayerScore], killer: u64, victim: u64) -> Vec<String>;
+
+    /// Winner by score once the frag limit is reached, or when forced (time is up).
+    fn winner(&self, players: &[PlayerScore], frag_limit: u32, force: bool) -> Option<Option<String>>;
+
+    /// Called with every position a player reports. Returns announcements for everyone.
+    fn on_player_moved(&mut self, _players: &mut [PlayerScore], _id: u64, _x: f32, _y: f32) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn on_leave(&mut self, _id: u64) {}
+
+    /// Called periodically by the server clock. Returns announcements for everyone.
+    fn tick(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Puts the mode back to its starting state for a new match.
+    fn reset(&mut self) {}
+
+    fn flags(&self) -> Vec<FlagInfo> {
+        Vec::new()
+    }
+}
+
+fn count_kill(players: &mut [PlayerScore], killer: u64, victim: u64, teamkill_penalty: bool) {
+    let victim_team = players.iter().find(|p| p.id == victim).and_then(|p| p.team);
+    if let Some(player) = players.iter_mut().find(|p| p.id == victim) {
+        player.deaths += 1;
+    }
+    if let Some(player) = players.iter_mut().find(|p| p.id == killer) {
+        if killer == victim || (teamkill_penalty && player.team.is_some() && player.team == victim_team) {
+            player.score -= 1;
+        } else {
+            player.kills += 1;
+            player.score += 1;
+        }
+    }
+}
+
+pub struct FreeForAll;
+
+impl GameMode for FreeForAll {
+    fn kind(&self) -> GameModeKind {
+        GameModeKind::FreeForAll
+    }
+
+    fn assign_team(&self, _players: &[PlayerScore]) -> Option<Team> {
+        None
+    }
+
+    fn on_kill(&mut self, players: &mut [PlayerScore], killer: u64, victim: u64) -> Vec<String> {
+        count_kill(players, killer, victim, false);
+        Vec::new()
+    }
+
+    fn winner(&self, players: &[PlayerScore], frag_limit: u32, force: bool) -> Option<Option<String>> {
+        // Forced to end with nobody left, which is a draw
+        let Some(best) = players.iter().map(|p| p.score).max() else {
+            return force.then_some(None);
+        };
+        if !force && (frag_limit == 0 || best < frag_limit as i32) {
+            return None;
+        }
+        let mut leaders = players.iter().filter(|p| p.score == best);
+        let first = leaders.next().map(|p| p.name.clone());
+        Some(if leaders.next().is_some() { None } else { first })
+    }
+}
+
+pub struct TeamDeathmatch;
+
+impl TeamDeathmatch {
+    pub fn team_score(players: &[PlayerScore], team: Team) -> i32 {
+        players.iter().filter(|p| p.team == Some(team)).map(|p| p.score).sum()
+    }
+}
+
+impl GameMode for TeamDeathmatch {
+    fn kind(&self) -> GameModeKind {
+        GameModeKind::TeamDeathmatch
+    }
+
+    fn assign_team(&self, players: &[PlayerScore]) -> Option<Team> {
+        let red = players.iter().filter(|p| p.team == Some(Team::Red)).count();
+        let blue = players.iter().filter(|p| p.team == Some(Team::Blue)).count();
+        Some(if red <= blue { Team::Red } else { Team::Blue })
+    }
+
+    fn on_kill(&mut self, players: &mut [PlayerScore], killer: u64, victim: u64) -> Vec<String> {
+        count_kill(players, killer, victim, true);
+        Vec::new()
+    }
+
+    fn winner(&self, players: &[PlayerScore], frag_limit: u32, force: bool) -> Option<Option<String>> {
+        let red = Self::team_score(players, Team::Red);
+        let blue = Self::team_score(players, Team::Blue);
+        team_winner(red, blue, frag_limit as i32, force)
+    }
+}
+
+fn team_winner(red: i32, blue: i32, limit: i32, force: bool) -> Option<Option<String>> {
+    if !force && (limit == 0 || red.max(blue) < limit) {
+        return None;
+    }
+    Some(match red.cmp(&blue) {
+        std::cmp::Ordering::Greater => Some("Red team".to_string()),
+        std::cmp::Ordering::Less => Some("Blue team".to_string()),
+        std::cmp::Ordering::Equal => None,
+    })
+}
+
+struct Flag {
+    team: Team,
+    base: Vec2,
+    position: Vec2,
+    carrier: Option<u64>,
+    /// When the flag was dropped, if it is lying away from its base.
+    dropped_at: Option<Instant>,
+}
+
+impl Flag {
+    fn is_home(&self) -> bool {
+        self.carrier.is_none() && self.dropped_at.is_none()
+    }
+
+    fn go_home(&mut self) {
+        self.position = self.base;
+        self.carrier = None;
+        self.dropped_at = None;
+    }
+}
+
+/// Teams score by carrying the enemy flag to their own base while their flag is home.
+pub struct CaptureTheFlag {
+    flags: Vec<Flag>,
+    red_captures: u32,
+    blue_captures: u32,
+    /// Last reported position of every player, where a carried flag is dropped.
+    positions: HashMap<u64, Vec2>,
+}
+
+impl CaptureTheFlag {
+    /// Builds the mode from the map's flag bases, if it has one for each team.
+    pub fn from_map(map: &Map) -> Option<Self> {
+        let base = |team: Team| {
+            map.objects
+                .iter()
+                .find(|object| object.kind == ObjectKind::FlagBase(team))
+                .map(|object| object.shape.center())
+        };
+        let flags = [Team::Red, Team::Blue]
+            .into_iter()
+            .map(|team| {
+                base(team).map(|base| Flag { team, base, position: base, carrier: None, dropped_at: None })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { flags, red_captures: 0, blue_captures: 0, positions: HashMap::new() })
+    }
+
+    fn drop_flag(&mut self, id: u64) -> Option<Team> {
+        let position = self.positions.get(&id).copied();
+        let flag = self.flags.iter_mut().find(|flag| flag.carrier == Some(id))?;
+        flag.carrier = None;
+        flag.position = position.unwrap_or(flag.base);
+        flag.dropped_at = Some(Instant::now());
+        Some(flag.team)
+    }
+}
+
+impl GameMode for CaptureTheFlag {
+    fn kind(&self) -> GameModeKind {
+        GameModeKind::CaptureTheFlag
+    }
+
+    fn assign_team(&self, players: &[PlayerScore]) -> Option<Team> {
+        TeamDeathmatch.assign_team(players)
+    }
+
+    fn on_kill(&mut self, players: &mut [PlayerScore], killer: u64, victim: u64) -> Vec<String> {
+        count_kill(players, killer, victim, true);
+        let name = players.iter().find(|p| p.id == victim).map(|p| p.name.clone()).unwrap_or_default();
+        match self.drop_flag(victim) {
+            Some(team) => vec![format!("{} dropped the {:?} flag", name, team)],
+            None => Vec::new(),
+        }
+    }
+
+    fn winner(&self, _players: &[PlayerScore], frag_limit: u32, force: bool) -> Option<Option<String>> {
+        team_winner(self.red_captures as i32, self.blue_captures as i32, frag_limit as i32, force)
+    }
+
+    fn on_player_moved(&mut self, players: &mut [PlayerScore], id: u64, x: f32, y: f32) -> Vec<String> {
+        let position = Vec2::new(x, y);
+        self.positions.insert(id, position);
+        let Some(player) = players.iter_mut().find(|p| p.id == id) else {
+            return Vec::new();
+        };
+        let Some(team) = player.team else {
+            return Vec::new();
+        };
+
+        let mut events = Vec::new();
+        for flag in &mut self.flags {
+            if flag.carrier == Some(id) {
+                flag.position = position;
+                continue;
+            }
+            if flag.carrier.is_some() || flag.position.distance(position) > FLAG_TOUCH_RADIUS {
+                continue;
+            }
+            if flag.team != team {
+                flag.carrier = Some(id);
+                flag.dropped_at = None;
+                events.push(format!("{} took the {:?} flag!", player.name, flag.team));
+            } else if flag.dropped_at.is_some() {
+                flag.go_home();
+                events.push(format!("{} returned the {:?} flag", player.name, flag.team));
+            }
+        }
+
+        let own_flag_home = self.flags.iter().any(|flag| flag.team == team && flag.is_home());
+        let at_own_base = self
+            .flags
+            .iter()
+            .any(|flag| flag.team == team && flag.base.distance(position) <= FLAG_TOUCH_RADIUS);
+        if own_flag_home
+            && at_own_base
+            && let Some(enemy_flag) = self.flags.iter_mut().find(|flag| flag.carrier == Some(id))
+        {
+            enemy_flag.go_home();
+            player.score += CAPTURE_SCORE;
+            match team {
+                Team::Red => self.red_captures += 1,
+                Team::Blue => self.blue_captures += 1,
+            }
+            events.push(format!(
+                "{} captured the {:?} flag! Red {} - {} Blue",
+                player.name, enemy_flag.team, self.red_captures, self.blue_captures
+            ));
+        }
+        events
+    }
+
+    fn on_leave(&mut self, id: u64) {
+        self.drop_flag(id);
+        self.positions.remove(&id);
+    }
+
+    fn tick(&mut self) -> Vec<String> {
+        let mut events = Vec::new();
+        for flag in &mut self.flags {
+            if flag.dropped_at.is_some_and(|at| at.elapsed().as_secs() >= FLAG_RETURN_TIME) {
+                flag.go_home();
+                events.push(format!("The {:?} flag returned to its base", flag.team));
+            }
+        }
+        events
+    }
+
+    fn reset(&mut self) {
+        for flag in &mut self.flags {
+            flag.go_home();
+        }
+        self.red_captures = 0;
+        self.blue_captures = 0;
+    }
+
+    fn flags(&self) -> Vec<FlagInfo> {
+        self.flags
+            .iter()
+            .map(|flag| FlagInfo { team: flag.team, x: flag.position.x, y: flag.position.y, carrier: flag.carrier })
+            .collect()
+    }
+}
+
+/// A match in progress on the server: the mode's rules plus everyone's score.
+pub struct Match {
+    pub settings: MatchSettings,
+    pub mode: Box<dyn GameMode>,
+    pub players: Vec<PlayerScore>,
+    pub phase: MatchPhase,
+    pub phase_started: Instant,
+    pub result: Option<MatchResult>,
+}
+
+impl Match {
+    pub fn new(settings: MatchSettings, map: &Map) -> Self {
+        Self {
+            mode: Self::mode_for(&settings, map),
+            settings,
+            players: Vec::new(),
+            phase: MatchPhase::Warmup,
+            phase_started: Instant::now(),
+            result: None,
+        }
+    }
+
+    fn mode_for(settings: &MatchSettings, map: &Map) -> Box<dyn GameMode> {
+        if !map.header.supports(settings.mode) {
+            eprintln!("Map \"{}\" isn't made for {:?}, playing it anyway", map.header.name, settings.mode);
+        }
+        match settings.mode {
+            GameModeKind::FreeForAll => Box::new(FreeForAll),
+            GameModeKind::TeamDeathmatch => Box::new(TeamDeathmatch),
+            GameModeKind::CaptureTheFlag => match CaptureTheFlag::from_map(map) {
+                Some(ctf) => Box::new(ctf),
+                None => {
+                    eprintln!("Map has no flag base for each team, playing team deathmatch instead");
+                    Box::new(TeamDeathmatch)
+                }
+            },
+        }
+    }
+
+    /// Seconds left in the current phase, for the phases that end on a timer.
+    fn phase_time_left(&self) -> Option<u32> {
+        let limit = match self.phase {
+            MatchPhase::Warmup => return None,
+            MatchPhase::Countdown => self.settings.countdown,
+            MatchPhase::Live if self.settings.time_limit > 0 => self.settings.time_limit,
+            MatchPhase::Live => return None,
+            MatchPhase::PostGame => self.settings.post_game_time,
+        };
+        Some(limit.saturating_sub(self.phase_started.elapsed().as_secs() as u32))
+    }
+
+    pub fn info_for(&self, id: u64) -> MatchInfo {
+        MatchInfo {
+            mode: self.mode.kind(),
+            frag_limit: self.settings.frag_limit,
+            time_limit: self.settings.time_limit,
+            friendly_fire: self.settings.friendly_fire,
+            team: self.players.iter().find(|p| p.id == id).and_then(|p| p.team),
+            phase: self.phase,
+            phase_time_left: self.phase_time_left(),
+            min_players: self.settings.min_players,
+        }
+    }
+
+    /// Adds a player if they aren't playing yet. Returns true if they were added.
+    pub fn join(&mut self, id: u64, name: &str) -> bool {
+        if self.players.iter().any(|p| p.id == id) {
+            return false;
+        }
+        let team = self.mode.assign_team(&self.players);
+        self.players.push(PlayerScore { id, name: name.to_string(), team, kills: 0, deaths: 0, score: 0 });
+        true
+    }
+
+    /// Removes a player and, in team modes, moves the last joined player of a team that
+    /// ended up two players bigger to the other team. Returns the id of the moved player.
+    pub fn leave(&mut self, id: u64) -> Option<u64> {
+        self.players.retain(|p| p.id != id);
+        self.mode.on_leave(id);
+
+        let red = self.players.iter().filter(|p| p.team == Some(Team::Red)).count();
+        let blue = self.players.iter().filter(|p| p.team == Some(Team::Blue)).count();
+        let (from, to) = if red > blue + 1 {
+            (Team::Red, Team::Blue)
+        } else if blue > red + 1 {
+            (Team::Blue, Team::Red)
+        } else {
+            return None;
+        };
+        let moved = self.players.iter_mut().rev().find(|p| p.team == Some(from))?;
+        moved.team = Some(to);
+        Some(moved.id)
+    }
+
+    pub fn record_kill(&mut self, killer: u64, victim: u64) -> Vec<String> {
+        if self.phase != MatchPhase::Live {
+            return Vec::new();
+        }
+        self.mode.on_kill(&mut self.players, killer, victim)
+    }
+
+    pub fn tick(&mut self) -> Vec<String> {
+        if self.phase != MatchPhase::Live {
+            return Vec::new();
+        }
+        self.mode.tick()
+    }
+
+    pub fn flags(&self) -> Vec<FlagInfo> {
+        self.mode.flags()
+    }
+
+    pub fn player_moved(&mut self, id: u64, x: f32, y: f32) -> Vec<String> {
+        if self.phase != MatchPhase::Live {
+            return Vec::new();
+        }
+        self.mode.on_player_moved(&mut self.players, id, x, y)
+    }
+
+    /// Ends the match if a limit was reached and returns the result when it just ended.
+    pub fn check_end(&mut self) -> Option<MatchResult> {
+        if self.phase != MatchPhase::Live {
+            return None;
+        }
+        let time_up = self.phase_time_left() == Some(0);
+        let winner = self.mode.winner(&self.players, self.settings.frag_limit, time_up)?;
+
+        let mut scores = self.players.clone();
+        scores.sort_by_key(|p| std::cmp::Reverse(p.score));
+        let result = MatchResult { winner, scores };
+        self.result = Some(result.clone());
+        self.set_phase(MatchPhase::PostGame);
+        Some(result)
+    }
+
+    /// Every player's score sorted best first, with pings looked up by player id.
+    pub fn scoreboard(&self, ping: impl Fn(u64) -> Option<u32>) -> Vec<ScoreboardEntry> {
+        let mut entries: Vec<ScoreboardEntry> = self
+            .players
+            .iter()
+            .map(|player| ScoreboardEntry { player: player.clone(), ping: ping(player.id) })
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.player.score));
+        entries
+    }
+
+    /// Moves between warmup, countdown and live as players come and go and the countdown
+    /// runs out, going back to warmup if too many players leave a live match. Returns the
+    /// new phase when it changed. Ending the match is up to `check_end`, and leaving the
+    /// post-game screen to `change_map`.
+    pub fn update(&mut self) -> Option<MatchPhase> {
+        let enough_players = self.players.len() >= self.settings.min_players as usize;
+        let next = match self.phase {
+            MatchPhase::Warmup if enough_players => MatchPhase::Countdown,
+            MatchPhase::Countdown if !enough_players => MatchPhase::Warmup,
+            MatchPhase::Countdown if self.phase_time_left() == Some(0) => {
+                self.start();
+                MatchPhase::Live
+            }
+            MatchPhase::Live if !enough_players => {
+                // Carried flags go home, scores are cleared again when the match restarts
+                self.mode.reset();
+                MatchPhase::Warmup
+            }
+            _ => return None,
+        };
+        self.set_phase(next);
+        Some(next)
+    }
+
+    /// Whether the end screen has been up long enough to move on to the next map.
+    pub fn post_game_over(&self) -> bool {
+        self.phase == MatchPhase::PostGame && self.phase_time_left() == Some(0)
+    }
+
+    /// Sets the match up on a new map with the same players and rules, back in warmup.
+    pub fn change_map(&mut self, map: &Map) {
+        self.mode = Self::mode_for(&self.settings, map);
+        self.result = None;
+        self.start();
+        self.set_phase(MatchPhase::Warmup);
+    }
+
+    fn set_phase(&mut self, phase: MatchPhase) {
+        self.phase = phase;
+        self.phase_started = Instant::now();
+    }
+
+    /// Clears scores for a fresh match.
+    fn start(&mut self) {
+        for player in &mut self.players {
+            player.kills = 0;
+            player.deaths = 0;
+            player.score = 0;
+        }
+        self.mode.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{MapObject, ObjectShape};
+    use crate::tileset::Tileset;
+
+    /// A live match of `mode` on an empty map with `players` joined in order, ids from 1.
+    fn live_match(mode: GameModeKind, players: &[&str]) -> Match {
+        let settings = MatchSettings { mode, min_players: 2, countdown: 0, ..Default::default() };
+        let mut game = Match::new(settings, &Map::new(4, 4, Tileset::default()));
+        for (id, name) in (1..).zip(players) {
+            game.join(id, name);
+        }
+        game.update();
+        assert_eq!(game.update(), Some(MatchPhase::Live));
+        game
+    }
+
+    fn score(game: &Match, id: u64) -> (u32, u32, i32) {
+        let player = game.players.iter().find(|p| p.id == id).unwrap();
+        (player.kills, player.deaths, player.score)
+    }
+
+    fn with_scores(scores: &[(Option<Team>, i32)]) -> Vec<PlayerScore> {
+        (0..)
+            .zip(scores)
+            .map(|(id, &(team, score))| PlayerScore { id, name: format!("p{}", id), team, kills: 0, deaths: 0, score })
+            .collect()
+    }
+
+    #[test]
+    fn suicides_cost_a_point() {
+        let mut game = live_match(GameModeKind::FreeForAll, &["a", "b"]);
+        game.record_kill(1, 1);
+        assert_eq!(score(&game, 1), (0, 1, -1));
+        game.record_kill(2, 1);
+        assert_eq!(score(&game, 2), (1, 0, 1));
+    }
+
+    #[test]
+    fn teamkills_only_cost_a_point_in_team_modes() {
+        let mut players = with_scores(&[(Some(Team::Red), 0), (Some(Team::Red), 0)]);
+        count_kill(&mut players, 0, 1, true);
+        assert_eq!((players[0].kills, players[0].score, players[1].deaths), (0, -1, 1));
+        count_kill(&mut players, 0, 1, false);
+        assert_eq!((players[0].kills, players[0].score), (1, 0));
+    }
+
+    #[test]
+    fn kills_only_count_while_live() {
+        let mut game = live_match(GameModeKind::FreeForAll, &["a", "b"]);
+        game.leave(2);
+        game.update();
+        game.record_kill(1, 2);
+        assert_eq!(score(&game, 1), (0, 0, 0));
+    }
+
+    #[test]
+    fn free_for_all_is_won_at_the_frag_limit_or_when_time_is_up() {
+        let players = with_scores(&[(None, 3), (None, 5)]);
+        assert_eq!(FreeForAll.winner(&players, 10, false), None);
+        assert_eq!(FreeForAll.winner(&players, 0, false), None);
+        assert_eq!(FreeForAll.winner(&players, 5, false), Some(Some("p1".to_string())));
+        assert_eq!(FreeForAll.winner(&players, 10, true), Some(Some("p1".to_string())));
+        // Tied leaders draw
+        let tied = with_scores(&[(None, 5), (None, 5), (None, 1)]);
+        assert_eq!(FreeForAll.winner(&tied, 5, false), Some(None));
+    }
+
+    #[test]
+    fn team_deathmatch_adds_up_team_scores() {
+        let players = with_scores(&[(Some(Team::Red), 3), (Some(Team::Red), 3), (Some(Team::Blue), 5)]);
+        assert_eq!(TeamDeathmatch.winner(&players, 10, false), None);
+        assert_eq!(TeamDeathmatch.winner(&players, 6, false), Some(Some("Red team".to_string())));
+        assert_eq!(TeamDeathmatch.winner(&players, 10, true), Some(Some("Red team".to_string())));
+        let tied = with_scores(&[(Some(Team::Red), 4), (Some(Team::Blue), 4)]);
+        assert_eq!(TeamDeathmatch.winner(&tied, 0, true), Some(None));
+    }
+
+    #[test]
+    fn teams_are_filled_in_turn_and_rebalanced_when_players_leave() {
+        let mut game = live_match(GameModeKind::TeamDeathmatch, &["a", "b", "c", "d"]);
+        let teams = |game: &Match| game.players.iter().map(|p| p.team.unwrap()).collect::<Vec<_>>();
+        assert_eq!(teams(&game), [Team::Red, Team::Blue, Team::Red, Team::Blue]);
+
+        // One apart is fine
+        assert_eq!(game.leave(2), None);
+        // Two apart moves the last player who joined the bigger team
+        assert_eq!(game.leave(4), Some(3));
+        assert_eq!(teams(&game), [Team::Red, Team::Blue]);
+    }
+
+    #[test]
+    fn matches_count_down_once_enough_players_joined() {
+        let settings = MatchSettings { min_players: 2, countdown: 10, ..Default::default() };
+        let mut game = Match::new(settings, &Map::new(4, 4, Tileset::default()));
+        game.join(1, "a");
+        assert_eq!(game.update(), None);
+        assert_eq!(game.phase, MatchPhase::Warmup);
+
+        game.join(2, "b");
+        assert_eq!(game.update(), Some(MatchPhase::Countdown));
+        assert_eq!(game.info_for(1).phase_time_left, Some(10));
+        // Still counting down
+        assert_eq!(game.update(), None);
+
+        game.leave(2);
+        assert_eq!(game.update(), Some(MatchPhase::Warmup));
+    }
+
+    #[test]
+    fn matches_go_live_with_fresh_scores_and_end_at_the_limit() {
+        let mut game = live_match(GameModeKind::FreeForAll, &["a", "b"]);
+        assert_eq!(game.info_for(1).phase_time_left, Some(600));
+        game.settings.frag_limit = 2;
+        game.record_kill(1, 2);
+        assert!(game.check_end().is_none());
+        game.record_kill(1, 2);
+
+        let result = game.check_end().unwrap();
+        assert_eq!(result.winner.as_deref(), Some("a"));
+        assert_eq!(game.phase, MatchPhase::PostGame);
+        // Only ends once, and nothing moves it on but the map change
+        assert!(game.check_end().is_none());
+        assert_eq!(game.update(), None);
+
+        game.change_map(&Map::new(4, 4, Tileset::default()));
+        assert_eq!((game.phase, score(&game, 1)), (MatchPhase::Warmup, (0, 0, 0)));
+    }
+
+    const RED_BASE: Vec2 = Vec2::new(100.0, 100.0);
+    const BLUE_BASE: Vec2 = Vec2::new(900.0, 100.0);
+
+    /// Capture the flag between player 0 and 2 on red and player 1 on blue.
+    fn ctf() -> (CaptureTheFlag, Vec<PlayerScore>) {
+        let mut map = Map::new(32, 8, Tileset::default());
+        for (team, base) in [(Team::Red, RED_BASE), (Team::Blue, BLUE_BASE)] {
+            let shape = ObjectShape::Rect { x: base.x - 20.0, y: base.y - 20.0, w: 40.0, h: 40.0 };
+            map.objects.push(MapObject { kind: ObjectKind::FlagBase(team), shape });
+        }
+        let players = with_scores(&[(Some(Team::Red), 0), (Some(Team::Blue), 0), (Some(Team::Red), 0)]);
+        (CaptureTheFlag::from_map(&map).unwrap(), players)
+    }
+
+    fn flag(ctf: &CaptureTheFlag, team: Team) -> &Flag {
+        ctf.flags.iter().find(|flag| flag.team == team).unwrap()
+    }
+
+    fn walk(ctf: &mut CaptureTheFlag, players: &mut [PlayerScore], id: u64, to: Vec2) -> Vec<String> {
+        ctf.on_player_moved(players, id, to.x, to.y)
+    }
+
+    #[test]
+    fn flags_are_taken_by_the_enemy_and_carried_along() {
+        let (mut ctf, mut players) = ctf();
+        // Touching your own flag at home does nothing
+        assert!(walk(&mut ctf, &mut players, 0, RED_BASE).is_empty());
+        assert!(flag(&ctf, Team::Red).is_home());
+
+        assert_eq!(walk(&mut ctf, &mut players, 1, RED_BASE), ["p1 took the Red flag!"]);
+        walk(&mut ctf, &mut players, 1, Vec2::new(400.0, 300.0));
+        let red = flag(&ctf, Team::Red);
+        assert_eq!((red.carrier, red.position), (Some(1), Vec2::new(400.0, 300.0)));
+    }
+
+    #[test]
+    fn flags_are_dropped_on_death_and_returned_by_teammates() {
+        let (mut ctf, mut players) = ctf();
+        walk(&mut ctf, &mut players, 1, RED_BASE);
+        let dropped_at = Vec2::new(400.0, 300.0);
+        walk(&mut ctf, &mut players, 1, dropped_at);
+
+        assert_eq!(ctf.on_kill(&mut players, 0, 1), ["p1 dropped the Red flag"]);
+        let red = flag(&ctf, Team::Red);
+        assert_eq!((red.carrier, red.position), (None, dropped_at));
+        assert!(!red.is_home());
+
+        assert_eq!(walk(&mut ctf, &mut players, 2, dropped_at), ["p2 returned the Red flag"]);
+        assert!(flag(&ctf, Team::Red).is_home());
+    }
+
+    #[test]
+    fn dropped_flags_return_on_their_own() {
+        let (mut ctf, mut players) = ctf();
+        walk(&mut ctf, &mut players, 1, RED_BASE);
+        walk(&mut ctf, &mut players, 1, Vec2::new(400.0, 300.0));
+        ctf.on_kill(&mut players, 0, 1);
+        assert!(ctf.tick().is_empty());
+
+        let red = ctf.flags.iter_mut().find(|flag| flag.team == Team::Red).unwrap();
+        red.dropped_at = Instant::now().checked_sub(std::time::Duration::from_secs(FLAG_RETURN_TIME));
+        assert_eq!(ctf.tick(), ["The Red flag returned to its base"]);
+        assert!(flag(&ctf, Team::Red).is_home());
+    }
+
+    #[test]
+    fn flags_are_only_captured_while_your_own_is_home() {
+        let (mut ctf, mut players) = ctf();
+        walk(&mut ctf, &mut players, 0, BLUE_BASE);
+        walk(&mut ctf, &mut players, 1, RED_BASE);
+        walk(&mut ctf, &mut players, 1, Vec2::new(400.0, 300.0));
+
+        // The red flag is away, so bringing the blue one home doesn't count yet
+        assert!(walk(&mut ctf, &mut players, 0, RED_BASE).is_empty());
+        assert_eq!(flag(&ctf, Team::Blue).carrier, Some(0));
+
+        ctf.on_kill(&mut players, 2, 1);
+        walk(&mut ctf, &mut players, 2, Vec2::new(400.0, 300.0));
+        assert_eq!(
+            walk(&mut ctf, &mut players, 0, RED_BASE + Vec2::X),
+            ["p0 captured the Blue flag! Red 1 - 0 Blue"]
+        );
+        assert!(flag(&ctf, Team::Blue).is_home());
+        assert_eq!((players[0].score, ctf.red_captures), (CAPTURE_SCORE, 1));
+        assert_eq!(ctf.winner(&players, 1, false), Some(Some("Red team".to_string())));
+    }
+
+    #[test]
+    fn empty_matches_end_in_a_draw_when_forced() {
+        assert_eq!(FreeForAll.winner(&[], 20, false), None);
+        assert_eq!(FreeForAll.winner(&[], 20, true), Some(None));
+    }
+
+    #[test]
+    fn live_matches_go_back_to_warmup_without_enough_players() {
+        let mut game = live_match(GameModeKind::FreeForAll, &["a", "b"]);
+        game.leave(2);
+        assert_eq!(game.update(), Some(MatchPhase::Warmup));
+        assert_eq!(game.check_end().map(|result| result.winner), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Where clients keep their own best lap per map.
+pub const PERSONAL_GHOSTS: &str = "ghosts/personal";
+/// Where the server keeps the record holder's lap per map.
+pub const RECORD_GHOSTS: &str = "ghosts/record";
+
+/// Seconds between two recorded position samples.
+const SAMPLE_INTERVAL: f32 = 0.05;
+
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct GhostSample {
+    /// Seconds since the start of the lap.
+    pub t: f32,
+    pub x: f32,
+    pub y: f32,
+    pub dir: bool,
+}
+
+/// A recorded race lap that can be played back as a translucent player.
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct Ghost {
+    pub name: String,
+    /// Lap time in seconds.
+    pub time: f32,
+    pub samples: Vec<GhostSample>,
+}
+
+impl Ghost {
+    /// Interpolated position and facing `t` seconds into the lap, if the lap lasted that long.
+    pub fn position_at(&self, t: f32) -> Option<(f32, f32, bool)> {
+        let next = self.samples.iter().position(|sample| sample.t >= t)?;
+        let b = &self.samples[next];
+        if next == 0 {
+            return Some((b.x, b.y, b.dir));
+        }
+        let a = &self.samples[next - 1];
+        let f = if b.t > a.t { (t - a.t) / (b.t - a.t) } else { 1.0 };
+        Some((a.x + (b.x - a.x) * f, a.y + (b.y - a.y) * f, a.dir))
+    }
+
+    /// Most samples a `GhostRecorder` takes during a lap lasting `time` seconds.
+    pub fn max_samples(time: f32) -> usize {
+        (time.max(0.0) / SAMPLE_INTERVAL).ceil() as usize + 2
+    }
+
+    /// Loads the ghost saved under `folder` for the map with the given hash.
+    pub fn load(folder: &str, map_hash: &str) -> Option<Ghost> {
+        let data = fs::read_to_string(Self::path(folder, map_hash)).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    pub fn save(&self, folder: &str, map_hash: &str) {
+        if let Err(e) = fs::create_dir_all(folder)
+            .and_then(|_| fs::write(Self::path(folder, map_hash), serde_json::to_string(self).unwrap()))
+        {
+            eprintln!("Failed to save ghost: {}", e);
+        }
+    }
+
+    fn path(folder: &str, map_hash: &str) -> PathBuf {
+        PathBuf::from(folder).join(format!("{}.json", map_hash))
+    }
+}
+
+/// Collects position samples of the local player during a lap.
+#[derive(Default)]
+pub struct GhostRecorder {
+    samples: Vec<GhostSample>,
+}
+
+impl GhostRecorder {
+    /// Records the player's position at lap time `t`, skipping samples closer than
+    /// `SAMPLE_INTERVAL` to the previous one. A `t` lower than the last sample means the
+    /// lap was restarted, so the previous samples are discarded.
+    pub fn record(&mut self, t: f32, x: f32, y: f32, dir: bool) {
+        if let Some(last) = self.samples.last() {
+            if t < last.t {
+                self.samples.clear();
+            } else if t - last.t < SAMPLE_INTERVAL {
+                return;
+            }
+        }
+        self.samples.push(GhostSample { t, x, y, dir });
+    }
+
+    /// Turns the samples recorded so far into a ghost for a finished lap.
+    pub fn finish(&mut self, name: &str, time: f32, x: f32, y: f32, dir: bool) -> Ghost {
+        self.samples.push(GhostSample { t: time, x, y, dir });
+        Ghost {
+            name: name.to_string(),
+            time,
+            samples: std::mem::take(&mut self.samples),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::item::Item;
+use crate::map::{LayerKind, Map, MapObject, Tile};
+
+/// Undo steps kept before the oldest ones are dropped.
+const MAX_STEPS: usize = 200;
+
+/// A change made to a map, with what it replaced.
+#[derive(Clone, Debug, PartialEq)]
+enum Change {
+    Tile { layer: LayerKind, x: usize, y: usize, before: Tile, after: Tile },
+    Items { before: Vec<Item>, after: Vec<Item> },
+    Objects { before: Vec<MapObject>, after: Vec<MapObject> },
+}
+
+impl Change {
+    fn apply(&self, map: &mut Map, undo: bool) {
+        match self {
+            Change::Tile { layer, x, y, before, after } => {
+                if let Some(tile) = map.layer_mut(*layer).get_mut(*x, *y) {
+                    *tile = if undo { *before } else { *after };
+                }
+            }
+            Change::Items { before, after } => map.items.clone_from(if undo { before } else { after }),
+            Change::Objects { before, after } => map.objects.clone_from(if undo { before } else { after }),
+        }
+    }
+}
+
+/// Undo and redo steps of the mapping tool. Edits go through it, and everything changed
+/// until `end_stroke` is undone in one go.
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    /// Changes of the stroke in progress, in the order they were made.
+    stroke: Vec<Change>,
+}
+
+impl History {
+    /// Replaces a tile, doing nothing if it is already `tile` or outside the map.
+    pub fn set_tile(&mut self, map: &mut Map, layer: LayerKind, x: usize, y: usize, tile: Tile) {
+        let Some(current) = map.layer_mut(layer).get_mut(x, y) else {
+            return;
+        };
+        if *current == tile {
+            return;
+        }
+        self.stroke.push(Change::Tile { layer, x, y, before: *current, after: tile });
+        *current = tile;
+    }
+
+    pub fn edit_items(&mut self, map: &mut Map, edit: impl FnOnce(&mut Vec<Item>)) {
+        let before = map.items.clone();
+        edit(&mut map.items);
+        if map.items != before {
+            self.stroke.push(Change::Items { before, after: map.items.clone() });
+        }
+    }
+
+    pub fn edit_objects(&mut self, map: &mut Map, edit: impl FnOnce(&mut Vec<MapObject>)) {
+        let before = map.objects.clone();
+        edit(&mut map.objects);
+        if map.objects != before {
+            self.stroke.push(Change::Objects { before, after: map.objects.clone() });
+        }
+    }
+
+    /// Closes the stroke in progress, making it a single undo step.
+    pub fn end_stroke(&mut self) {
+        if self.stroke.is_empty() {
+            return;
+        }
+        self.undo.push(std::mem::take(&mut self.stroke));
+        if self.undo.len() > MAX_STEPS {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || !self.stroke.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn undo(&mut self, map: &mut Map) {
+        self.end_stroke();
+        if let Some(step) = self.undo.pop() {
+            for change in step.iter().rev() {
+                change.apply(map, true);
+            }
+            self.redo.push(step);
+        }
+    }
+
+    pub fn redo(&mut self, map: &mut Map) {
+        self.end_stroke();
+        if let Some(step) = self.redo.pop() {
+            for change in &step {
+                change.apply(map, false);
+            }
+            self.undo.push(step);
+        }
+    }
+
+    /// Forgets every step, for edits that can't be undone such as resizing the map.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.stroke.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::Weapon;
+    use crate::map::{ObjectKind, ObjectShape, EMPTY_TILE};
+    use crate::tileset::Tileset;
+
+    const ROCK: Tile = Tile { id: 2, variant: 0, rotation: 0 };
+
+    fn map() -> Map {
+        Map::new(4, 4, Tileset::default())
+    }
+
+    fn tile(map: &Map, x: usize, y: usize) -> Tile {
+        *map.layer(LayerKind::Ground).get(x, y).unwrap()
+    }
+
+    #[test]
+    fn a_stroke_is_undone_at_once() {
+        let mut map = map();
+        let mut history = History::default();
+        for x in 0..3 {
+            history.set_tile(&mut map, LayerKind::Ground, x, 0, ROCK);
+            // Held over the same tile for several frames
+            history.set_tile(&mut map, LayerKind::Ground, x, 0, ROCK);
+        }
+        history.end_stroke();
+        history.set_tile(&mut map, LayerKind::Ground, 3, 3, ROCK);
+        history.end_stroke();
+
+        history.undo(&mut map);
+        assert_eq!(tile(&map, 3, 3), EMPTY_TILE);
+        assert_eq!(tile(&map, 2, 0), ROCK);
+        history.undo(&mut map);
+        assert!((0..3).all(|x| tile(&map, x, 0) == EMPTY_TILE));
+        assert!(!history.can_undo());
+
+        history.redo(&mut map);
+        assert!((0..3).all(|x| tile(&map, x, 0) == ROCK));
+        assert_eq!(tile(&map, 3, 3), EMPTY_TILE);
+    }
+
+    #[test]
+    fn painting_over_a_tile_twice_undoes_to_the_first_value() {
+        let mut map = map();
+        let mut history = History::default();
+        let grass = Tile { id: 1, ..ROCK };
+        history.set_tile(&mut map, LayerKind::Ground, 1, 1, ROCK);
+        history.set_tile(&mut map, LayerKind::Ground, 1, 1, grass);
+        history.end_stroke();
+        history.undo(&mut map);
+        assert_eq!(tile(&map, 1, 1), EMPTY_TILE);
+        history.redo(&mut map);
+        assert_eq!(tile(&map, 1, 1), grass);
+    }
+
+    #[test]
+    fn new_edits_drop_the_redo_steps() {
+        let mut map = map();
+        let mut history = History::default();
+        history.edit_items(&mut map, |items| items.push(Weapon::ak47(10.0, 10.0, false)));
+        history.end_stroke();
+        history.undo(&mut map);
+        assert!(map.items.is_empty());
+        assert!(history.can_redo());
+
+        let object = MapObject { kind: ObjectKind::StartLine, shape: ObjectShape::Rect { x: 0.0, y: 0.0, w: 5.0, h: 5.0 } };
+        history.edit_objects(&mut map, |objects| objects.push(object.clone()));
+        history.end_stroke();
+        assert!(!history.can_redo());
+        history.redo(&mut map);
+        assert!(map.items.is_empty());
+
+        history.undo(&mut map);
+        assert!(map.objects.is_empty());
+        history.redo(&mut map);
+        assert_eq!(map.objects, [object]);
+    }
+
+    #[test]
+    fn edits_that_change_nothing_are_not_recorded() {
+        let mut map = map();
+        let mut history = History::default();
+        history.set_tile(&mut map, LayerKind::Ground, 0, 0, EMPTY_TILE);
+        history.set_tile(&mut map, LayerKind::Ground, 9, 9, ROCK);
+        history.edit_objects(&mut map, |objects| objects.retain(|_| false));
+        history.end_stroke();
+        assert!(!history.can_undo());
+    }
+}
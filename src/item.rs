@@ -1,8 +1,12 @@
-use std::time::Instant;
+use std::f32::consts::PI;
 
-use macroquad::{miniquad::TextureFormat, prelude::ImageFormat, rand::gen_range, texture::Texture2D};
+use macroquad::{math::Vec2, rand::gen_range};
 use serde::{Serialize, Deserialize};
 use bincode::{Encode, Decode};
+
+/// Size of the square items are drawn in and picked up from, from their position.
+pub const ITEM_SIZE: f32 = 32.0;
+
 #[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
 pub enum WeaponKind {
     Ak47,
@@ -10,6 +14,10 @@ pub enum WeaponKind {
 
 }
 
+impl WeaponKind {
+    pub const ALL: [WeaponKind; 2] = [WeaponKind::Ak47, WeaponKind::Magnum];
+}
+
 #[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
 pub enum AmmoType {
     Small,
@@ -23,13 +31,32 @@ pub struct Weapon {
     pub bullets_per_shot: u32,
     pub magazine: u32,
     pub magazine_size: u32,
+    /// Reserve ammo, loaded into the magazine on reload.
+    pub ammo: u32,
     pub reload_time: u32,
+    /// Max deviation of a shot from the aim direction when standing still, in degrees.
     pub spread: f32,
+    /// Spread added by each shot, in degrees.
+    pub recoil_per_shot: f32,
+    /// Cap on the spread accumulated from sustained fire, in degrees.
+    pub max_recoil: f32,
+    /// How fast accumulated recoil wears off, in degrees per second.
+    pub recoil_recovery: f32,
+    /// Spread added when moving at full speed, in degrees.
+    pub move_spread: f32,
+    /// Spread currently accumulated from firing, in degrees. Runtime state, so not saved
+    /// with maps.
+    #[serde(skip)]
+    pub recoil: f32,
     pub is_auto: bool,
     pub firerate: f32,
     pub last_shot_time: f32,
     pub ammo_type: AmmoType,
-    pub shotoffset: (f32, f32)
+    pub shotoffset: (f32, f32),
+    /// Max distance in pixels a shot travels before it fades out.
+    pub range: f32,
+    /// Fraction of the damage lost at max range, applied linearly over distance.
+    pub falloff: f32,
 }
 impl Weapon {
     pub fn ak47(px: f32, py: f32, ppicked: bool) -> Item {
@@ -47,13 +74,21 @@ impl Weapon {
                 bullets_per_shot: 1,
                 magazine: 30,
                 magazine_size: 30,
+                ammo: 90,
                 reload_time: 2,
-                spread: 10.0,
+                spread: 2.0,
+                recoil_per_shot: 0.8,
+                max_recoil: 8.0,
+                recoil_recovery: 10.0,
+                move_spread: 4.0,
+                recoil: 0.0,
                 is_auto: true,
                 firerate: 0.1,
                 last_shot_time: 0.0,
                 ammo_type: AmmoType::Medium,
-                shotoffset: (20.0, 8.0)
+                shotoffset: (20.0, 8.0),
+                range: 600.0,
+                falloff: 0.5,
             }),
         }
     }
@@ -72,18 +107,78 @@ impl Weapon {
                 bullets_per_shot: 1,
                 magazine: 6,
                 magazine_size: 6,
+                ammo: 18,
                 reload_time: 4,
-                spread: 2.0,
+                spread: 1.0,
+                recoil_per_shot: 6.0,
+                max_recoil: 12.0,
+                recoil_recovery: 12.0,
+                move_spread: 6.0,
+                recoil: 0.0,
                 is_auto: true,
                 firerate: 0.1,
                 last_shot_time: 0.0,
                 ammo_type: AmmoType::Medium,
-                shotoffset: (20.0, 8.0)
+                shotoffset: (20.0, 8.0),
+                range: 400.0,
+                falloff: 0.7,
             }),
         }
 
     }
 
+    pub fn item_of_kind(kind: &WeaponKind, px: f32, py: f32, ppicked: bool) -> Item {
+        match kind {
+            WeaponKind::Ak47 => Weapon::ak47(px, py, ppicked),
+            WeaponKind::Magnum => Weapon::magnum(px, py, ppicked),
+        }
+    }
+
+    /// Default stats for a weapon kind, used when only the kind is known (e.g. remote shots).
+    pub fn from_kind(kind: &WeaponKind) -> Weapon {
+        let ItemKind::Weapon(weapon) = Weapon::item_of_kind(kind, 0.0, 0.0, false).kind;
+        weapon
+    }
+
+    /// Rotation to draw the held weapon with and whether it has to be mirrored, so it
+    /// points along `aim` (radians) without ending up upside down.
+    pub fn held_rotation(aim: f32) -> (f32, bool) {
+        if aim.cos() >= 0.0 { (aim, true) } else { (aim - PI, false) }
+    }
+
+    /// World position of the muzzle for a weapon held at (x, y) and pointed along `aim`.
+    pub fn muzzle(&self, x: f32, y: f32, aim: f32) -> Vec2 {
+        let (rotation, facing_right) = Weapon::held_rotation(aim);
+        let offset_x = if facing_right { self.shotoffset.0 } else { -self.shotoffset.0 };
+        Vec2::new(x, y) + Vec2::from_angle(rotation).rotate(Vec2::new(offset_x, self.shotoffset.1))
+    }
+
+    pub fn reload(&mut self) {
+        let loaded = (self.magazine_size - self.magazine).min(self.ammo);
+        self.magazine += loaded;
+        self.ammo -= loaded;
+    }
+
+    /// Current spread in degrees, given how fast the shooter moves relative to full speed.
+    pub fn current_spread(&self, speed_ratio: f32) -> f32 {
+        self.spread + self.recoil + self.move_spread * speed_ratio.clamp(0.0, 1.0)
+    }
+
+    pub fn add_recoil(&mut self) {
+        self.recoil = (self.recoil + self.recoil_per_shot).min(self.max_recoil);
+    }
+
+    pub fn recover(&mut self, dt: f32) {
+        self.recoil = (self.recoil - self.recoil_recovery * dt).max(0.0);
+    }
+
+    pub fn damage_at(&self, distance: f32) -> u32 {
+        if distance > self.range {
+            return 0;
+        }
+        let factor = 1.0 - self.falloff * (distance / self.range);
+        (self.damage as f32 * factor).round() as u32
+    }
 }
 #[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
 pub enum ItemKind {
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::fs;
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+const LEADERBOARDS_FILE: &str = "leaderboards.json";
+
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    pub name: String,
+    /// Lap time in seconds.
+    pub time: f32,
+}
+
+/// Best lap time of every player, per map hash. Kept by the server in `leaderboards.json`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Leaderboards {
+    maps: HashMap<String, Vec<LeaderboardEntry>>,
+}
+
+impl Leaderboards {
+    pub fn load() -> Self {
+        match fs::read_to_string(LEADERBOARDS_FILE) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}, starting empty", LEADERBOARDS_FILE, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        if let Err(e) = fs::write(LEADERBOARDS_FILE, serde_json::to_string(self).unwrap()) {
+            eprintln!("Failed to save {}: {}", LEADERBOARDS_FILE, e);
+        }
+    }
+
+    /// Records a lap, keeping only each player's best. Returns true if it is the new map record.
+    pub fn submit(&mut self, map_hash: &str, name: &str, time: f32) -> bool {
+        let entries = self.maps.entry(map_hash.to_string()).or_default();
+        let is_record = entries.first().is_none_or(|best| time < best.time);
+
+        match entries.iter_mut().find(|entry| entry.name == name) {
+            Some(entry) if entry.time <= time => return false,
+            Some(entry) => entry.time = time,
+            None => entries.push(LeaderboardEntry { name: name.to_string(), time }),
+        }
+        entries.sort_by(|a, b| a.time.total_cmp(&b.time));
+        is_record
+    }
+
+    pub fn top(&self, map_hash: &str, n: usize) -> Vec<LeaderboardEntry> {
+        self.maps
+            .get(map_hash)
+            .map(|entries| entries.iter().take(n).cloned().collect())
+            .unwrap_or_default()
+    }
+}
@@ -1,15 +1,27 @@
 use std::{io, process};
 
 mod debugutils;
+mod gamemode;
+mod ghost;
+mod history;
 mod client;
 mod common;
 mod item;
+mod leaderboard;
 mod map;
+mod map_files;
+mod movement;
 mod mapping_tool;
 mod packet;
+mod paint;
 mod player;
 mod server;
 mod resources;
+mod race;
+mod render;
+mod shot;
+mod tilegrid;
+mod tileset;
 fn main() {
     println!("Welcome to Zone zero!\nChoose an option:");
     println!("1. Start a server");
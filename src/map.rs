@@ -1,50 +1,756 @@
+use std::collections::HashMap;
+
 use bincode::{Decode, Encode};
+use macroquad::math::{Rect, Vec2};
 use serde::{Deserialize, Serialize};
-use crate::item::Item;
-#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, Copy, PartialEq)]
-pub enum TileKind {
-    Grass,
-    Rock,
-    Empty,
-}
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::common::TILE_SIZE;
+use crate::gamemode::{GameModeKind, Team};
+use crate::item::{Item, ItemKind, Weapon, WeaponKind};
+use crate::tilegrid::{TileGrid, MAX_TILES};
+use crate::tileset::{TileDef, Tileset};
+
+/// Version of the map file format written by `Map::to_json`.
+pub const MAP_FORMAT_VERSION: u32 = 5;
+
+/// Upgrades the fields of a map file from the version at its index to the next one.
+/// Files without a version are version 0.
+const MIGRATIONS: [fn(&mut serde_json::Map<String, Value>); MAP_FORMAT_VERSION as usize] = [migrate_v0, migrate_v1, migrate_v2, migrate_v3, migrate_v4];
+
 #[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, Copy, PartialEq)]
 pub struct Tile {
-    pub collision: bool,
-    pub kind: TileKind,
+    /// Id of the tile type in the tileset.
+    pub id: u16,
+    /// Which of the type's textures to draw, wrapping around if it has fewer.
+    pub variant: u8,
+    /// Quarter turns clockwise the texture and collision shape are turned by.
+    pub rotation: u8,
+}
+pub const EMPTY_TILE: Tile = Tile { id: 0, variant: 0, rotation: 0 };
+
+/// Tile layers of a map, from the bottom up.
+#[derive(Serialize, Deserialize, Clone, Copy, Encode, Decode, Debug, PartialEq, Eq, Hash)]
+pub enum LayerKind {
+    Ground,
+    Walls,
+    Decoration,
+    /// Drawn above players, e.g. roofs. Purely visual.
+    Overlay,
+}
+
+impl LayerKind {
+    pub const ALL: [LayerKind; 4] = [LayerKind::Ground, LayerKind::Walls, LayerKind::Decoration, LayerKind::Overlay];
+    /// Layers whose tiles collide, block bullets and block sight. Players walk on top of these.
+    pub const GAMEPLAY: [LayerKind; 3] = [LayerKind::Ground, LayerKind::Walls, LayerKind::Decoration];
 }
+
+/// A tile replaced while the map is played, e.g. destroyed by weapon fire.
+#[derive(Serialize, Deserialize, Clone, Copy, Encode, Decode, Debug, PartialEq)]
+pub struct TileChange {
+    pub layer: LayerKind,
+    pub x: u32,
+    pub y: u32,
+    pub tile: Tile,
+}
+
+/// An item taken off or put on the map while it is played.
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub enum ItemChange {
+    PickedUp(u64),
+    Dropped(Item),
+}
+
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct MapLayer {
+    pub kind: LayerKind,
+    pub tiles: TileGrid,
+}
+
 #[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
 pub enum ObjectKind {
     StartLine,
     FinishLine,
+    FlagBase(Team),
+}
+
+/// Region covered by a map object, in world coordinates.
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub enum ObjectShape {
+    Line { x1: f32, y1: f32, x2: f32, y2: f32 },
+    Rect { x: f32, y: f32, w: f32, h: f32 },
+}
+
+impl ObjectShape {
+    /// Whether moving from `from` to `to` crosses a line or enters a rect.
+    pub fn crossed(&self, from: Vec2, to: Vec2) -> bool {
+        match *self {
+            ObjectShape::Line { x1, y1, x2, y2 } => {
+                segments_intersect(from, to, Vec2::new(x1, y1), Vec2::new(x2, y2))
+            }
+            ObjectShape::Rect { x, y, w, h } => {
+                let rect = Rect::new(x, y, w, h);
+                !rect.contains(from) && rect.contains(to)
+            }
+        }
+    }
+
+    pub fn translate(&mut self, offset: Vec2) {
+        match self {
+            ObjectShape::Line { x1, y1, x2, y2 } => {
+                *x1 += offset.x;
+                *y1 += offset.y;
+                *x2 += offset.x;
+                *y2 += offset.y;
+            }
+            ObjectShape::Rect { x, y, .. } => {
+                *x += offset.x;
+                *y += offset.y;
+            }
+        }
+    }
+
+    /// Whether the whole shape lies within `bounds`, edges included.
+    pub fn within(&self, bounds: Rect) -> bool {
+        let inside = |x: f32, y: f32| (bounds.x..=bounds.right()).contains(&x) && (bounds.y..=bounds.bottom()).contains(&y);
+        match *self {
+            ObjectShape::Line { x1, y1, x2, y2 } => inside(x1, y1) && inside(x2, y2),
+            ObjectShape::Rect { x, y, w, h } => inside(x, y) && inside(x + w, y + h),
+        }
+    }
+
+    pub fn center(&self) -> Vec2 {
+        match *self {
+            ObjectShape::Line { x1, y1, x2, y2 } => Vec2::new((x1 + x2) / 2.0, (y1 + y2) / 2.0),
+            ObjectShape::Rect { x, y, w, h } => Vec2::new(x + w / 2.0, y + h / 2.0),
+        }
+    }
+
+    /// Whether `point` lies within `tolerance` of the shape.
+    pub fn near(&self, point: Vec2, tolerance: f32) -> bool {
+        match *self {
+            ObjectShape::Line { x1, y1, x2, y2 } => {
+                let (a, b) = (Vec2::new(x1, y1), Vec2::new(x2, y2));
+                let t = ((point - a).dot(b - a) / (b - a).length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+                point.distance(a + (b - a) * t) <= tolerance
+            }
+            ObjectShape::Rect { x, y, w, h } => {
+                Rect::new(x - tolerance, y - tolerance, w + tolerance * 2.0, h + tolerance * 2.0).contains(point)
+            }
+        }
+    }
+}
+
+fn segments_intersect(a1: Vec2, a2: Vec2, b1: Vec2, b2: Vec2) -> bool {
+    let da = a2 - a1;
+    let db = b2 - b1;
+    let denom = da.perp_dot(db);
+    if denom == 0.0 {
+        return false;
+    }
+    let t = (b1 - a1).perp_dot(db) / denom;
+    let u = (b1 - a1).perp_dot(da) / denom;
+    (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u)
+}
+
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct MapObject {
+    pub kind: ObjectKind,
+    pub shape: ObjectShape,
+}
+
+/// Descriptive information about a map, shown to players and used to match maps to modes.
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, Default, PartialEq)]
+pub struct MapHeader {
+    pub name: String,
+    pub author: String,
+    pub description: String,
+    /// Number of players the map plays best with, 0 if not set.
+    pub recommended_players: u32,
+    /// Game modes the map is made for. Empty means any mode.
+    pub modes: Vec<GameModeKind>,
+}
+
+impl MapHeader {
+    pub fn supports(&self, mode: GameModeKind) -> bool {
+        self.modes.is_empty() || self.modes.contains(&mode)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
 pub struct Map {
-    pub height: u32,
-    pub width: u32,
-    pub tiles: Vec<Vec<Tile>>,
+    pub header: MapHeader,
+    /// One layer of each kind, in the order of `LayerKind::ALL`, all of the same size.
+    pub layers: Vec<MapLayer>,
     pub items: Vec<Item>,
+    #[serde(default)]
+    pub objects: Vec<MapObject>,
+    /// Definitions of the tile ids used by `layers`. Not part of map files: the tileset file
+    /// is read when a map is loaded and sent to clients along with the map.
+    #[serde(skip)]
+    pub tileset: Tileset,
+    /// Damage dealt so far to destructible tiles, by layer and tile position. Not saved.
+    #[serde(skip)]
+    pub tile_damage: HashMap<(LayerKind, usize, usize), u32>,
 }
 
 impl Map {
-    pub fn get_tile(&self, x: usize, y: usize) -> Option<&Tile> {
-        self.tiles.get(y).and_then(|row| row.get(x))
+    /// Hex SHA-256 identifying the map independently of its file name, used as the key of
+    /// its leaderboards and ghosts. The header is left out so renaming a map keeps them.
+    /// Hashes a layout spelled out here rather than the encoded struct, so fields that don't
+    /// change how the map plays, like item ids or weapon stats, leave the key alone: size,
+    /// each layer's tiles row by row, the kind and position of items and the kind and shape
+    /// of objects.
+    pub fn hash(&self) -> String {
+        let mut bytes = Vec::new();
+        bytes.extend(self.width().to_le_bytes());
+        bytes.extend(self.height().to_le_bytes());
+        bytes.extend((self.layers.len() as u32).to_le_bytes());
+        for layer in &self.layers {
+            bytes.push(match layer.kind {
+                LayerKind::Ground => 0,
+                LayerKind::Walls => 1,
+                LayerKind::Decoration => 2,
+                LayerKind::Overlay => 3,
+            });
+            for (_, _, tile) in layer.tiles.region(0..self.width() as usize, 0..self.height() as usize) {
+                bytes.extend(tile.id.to_le_bytes());
+                bytes.extend([tile.variant, tile.rotation]);
+            }
+        }
+        bytes.extend((self.items.len() as u32).to_le_bytes());
+        for item in &self.items {
+            let ItemKind::Weapon(weapon) = &item.kind;
+            bytes.extend([0, match weapon.weapon_kind {
+                WeaponKind::Ak47 => 0,
+                WeaponKind::Magnum => 1,
+            }]);
+            bytes.extend(item.x.to_le_bytes());
+            bytes.extend(item.y.to_le_bytes());
+        }
+        bytes.extend((self.objects.len() as u32).to_le_bytes());
+        for object in &self.objects {
+            bytes.extend(match object.kind {
+                ObjectKind::StartLine => [0, 0],
+                ObjectKind::FinishLine => [1, 0],
+                ObjectKind::FlagBase(Team::Red) => [2, 0],
+                ObjectKind::FlagBase(Team::Blue) => [2, 1],
+            });
+            let (tag, values) = match object.shape {
+                ObjectShape::Line { x1, y1, x2, y2 } => (0, [x1, y1, x2, y2]),
+                ObjectShape::Rect { x, y, w, h } => (1, [x, y, w, h]),
+            };
+            bytes.push(tag);
+            values.iter().for_each(|value| bytes.extend(value.to_le_bytes()));
+        }
+        format!("{:x}", Sha256::digest(&bytes))
+    }
+
+    /// Reads a map file of any known format version, migrating it to the current one.
+    pub fn from_json(data: &str, tileset: Tileset) -> Result<Map, String> {
+        let mut value: Value = serde_json::from_str(data).map_err(|e| e.to_string())?;
+        let fields = value.as_object_mut().ok_or("map file is not a JSON object")?;
+        let version = match fields.remove("version") {
+            Some(version) => version.as_u64().ok_or("map version is not a number")? as usize,
+            None => 0,
+        };
+        if version > MIGRATIONS.len() {
+            return Err(format!(
+                "map format version {} is newer than the supported {}",
+                version, MAP_FORMAT_VERSION
+            ));
+        }
+        for migrate in &MIGRATIONS[version..] {
+            migrate(fields);
+        }
+        let mut map: Map = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        let kinds: Vec<LayerKind> = map.layers.iter().map(|layer| layer.kind).collect();
+        if kinds != LayerKind::ALL {
+            return Err(format!("expected the layers {:?}, found {:?}", LayerKind::ALL, kinds));
+        }
+        if map.layers.iter().any(|layer| {
+            (layer.tiles.width(), layer.tiles.height()) != (map.width(), map.height())
+        }) {
+            return Err("layers have different sizes".to_string());
+        }
+        map.tileset = tileset;
+        Ok(map)
+    }
+
+    /// Writes the map in the current file format.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&MapFile { version: MAP_FORMAT_VERSION, map: self }).unwrap()
+    }
+
+    /// Maps with both a start and a finish line are played as races.
+    pub fn has_race(&self) -> bool {
+        self.objects.iter().any(|object| object.kind == ObjectKind::StartLine)
+            && self.objects.iter().any(|object| object.kind == ObjectKind::FinishLine)
+    }
+    pub fn width(&self) -> u32 {
+        self.layers[0].tiles.width()
+    }
+    pub fn height(&self) -> u32 {
+        self.layers[0].tiles.height()
+    }
+    pub fn layer(&self, kind: LayerKind) -> &TileGrid {
+        &self.layers[kind as usize].tiles
+    }
+    pub fn layer_mut(&mut self, kind: LayerKind) -> &mut TileGrid {
+        &mut self.layers[kind as usize].tiles
+    }
+    /// Definitions of the gameplay layers' tiles at a tile position, none outside the map.
+    pub fn tile_defs(&self, x: i64, y: i64) -> impl Iterator<Item = &TileDef> {
+        let inside = x >= 0 && y >= 0;
+        LayerKind::GAMEPLAY
+            .iter()
+            .filter_map(move |&kind| if inside { self.layer(kind).get(x as usize, y as usize) } else { None })
+            .map(|tile| self.tileset.get(tile.id))
+    }
+    /// Definition of the ground tile under a world position.
+    pub fn ground_at(&self, position: Vec2) -> Option<&TileDef> {
+        let tile = (position / TILE_SIZE).floor();
+        if tile.x < 0.0 || tile.y < 0.0 {
+            return None;
+        }
+        let tile = self.layer(LayerKind::Ground).get(tile.x as usize, tile.y as usize)?;
+        Some(self.tileset.get(tile.id))
+    }
+    /// Deals `amount` damage to the topmost gameplay tile blocking bullets at (x, y), if it is
+    /// destructible, replacing it once its durability runs out. Returns the change if it was destroyed.
+    pub fn damage_tile(&mut self, x: usize, y: usize, amount: u32) -> Option<TileChange> {
+        let (kind, tile) = LayerKind::GAMEPLAY
+            .iter()
+            .rev()
+            .filter_map(|&kind| Some((kind, *self.layer(kind).get(x, y)?)))
+            .find(|(_, tile)| self.tileset.get(tile.id).blocks_bullets)?;
+        let def = self.tileset.get(tile.id);
+        let durability = def.durability?;
+        let remains = Tile { id: def.destroyed_into, ..tile };
+        let damage = self.tile_damage.entry((kind, x, y)).or_insert(0);
+        *damage += amount;
+        if *damage < durability {
+            return None;
+        }
+        let change = TileChange { layer: kind, x: x as u32, y: y as u32, tile: remains };
+        self.apply_change(&change);
+        Some(change)
+    }
+    pub fn apply_change(&mut self, change: &TileChange) {
+        let (x, y) = (change.x as usize, change.y as usize);
+        if let Some(tile) = self.layer_mut(change.layer).get_mut(x, y) {
+            *tile = change.tile;
+        }
+        self.tile_damage.remove(&(change.layer, x, y));
+    }
+    /// Returns false if the change doesn't fit the map, i.e. the picked up item isn't on it
+    /// or the dropped one already is.
+    pub fn apply_item_change(&mut self, change: &ItemChange) -> bool {
+        match change {
+            ItemChange::PickedUp(id) => {
+                let Some(index) = self.items.iter().position(|item| item.id == *id) else {
+                    return false;
+                };
+                self.items.remove(index);
+            }
+            ItemChange::Dropped(item) => {
+                if self.items.iter().any(|other| other.id == item.id) {
+                    return false;
+                }
+                self.items.push(item.clone());
+            }
+        }
+        true
+    }
+    /// Tiles of a layer overlapping a world-space area, with their tile coordinates.
+    pub fn tiles_in(&self, kind: LayerKind, area: Rect) -> impl Iterator<Item = (usize, usize, &Tile)> {
+        let first = |start: f32| (start / TILE_SIZE).floor().max(0.0) as usize;
+        let last = |end: f32| (end / TILE_SIZE).ceil().max(0.0) as usize;
+        self.layer(kind).region(first(area.x)..last(area.right()), first(area.y)..last(area.bottom()))
+    }
+    /// Grows or crops the map by a number of tiles on each side (negative crops). Items and
+    /// objects move along so they stay over the same tiles, and those cropped off the map
+    /// are removed. Returns false, leaving the map as it is, if it would end up empty or over
+    /// `MAX_TILES`.
+    pub fn resize(&mut self, left: i32, top: i32, right: i32, bottom: i32) -> bool {
+        let width = self.width() as i64 + left as i64 + right as i64;
+        let height = self.height() as i64 + top as i64 + bottom as i64;
+        if width <= 0 || height <= 0 || width as u64 * height as u64 > MAX_TILES {
+            return false;
+        }
+        for layer in &mut self.layers {
+            layer.tiles.resize(left, top, right, bottom, EMPTY_TILE);
+        }
+        let offset = Vec2::new(left as f32, top as f32) * TILE_SIZE;
+        for item in &mut self.items {
+            item.x += offset.x;
+            item.y += offset.y;
+        }
+        for object in &mut self.objects {
+            object.shape.translate(offset);
+        }
+        let bounds = Rect::new(0.0, 0.0, self.width() as f32 * TILE_SIZE, self.height() as f32 * TILE_SIZE);
+        self.items.retain(|item| item.x >= 0.0 && item.y >= 0.0 && item.x < bounds.w && item.y < bounds.h);
+        self.objects.retain(|object| object.shape.within(bounds));
+        true
     }
-    pub fn new(height: u32, width: u32) -> Self {
+    pub fn new(width: u32, height: u32, tileset: Tileset) -> Self {
         Map {
-            height,
-            width,
-            tiles: vec![
-                vec![
-                    Tile {
-                        collision: false,
-                        kind: TileKind::Empty
-                    };
-                    width as usize
-                ];
-                height as usize
-            ],
+            header: MapHeader::default(),
+            layers: LayerKind::ALL
+                .iter()
+                .map(|&kind| MapLayer { kind, tiles: TileGrid::new(width, height, EMPTY_TILE) })
+                .collect(),
             items: Vec::new(),
+            objects: Vec::new(),
+            tileset,
+            tile_damage: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MapFile<'a> {
+    version: u32,
+    #[serde(flatten)]
+    map: &'a Map,
+}
+
+/// Version 0 is the unversioned dump of `Map`: it has no header, may have no objects, and
+/// its weapons lack every stat added since.
+fn migrate_v0(fields: &mut serde_json::Map<String, Value>) {
+    fields.insert("header".to_string(), serde_json::to_value(MapHeader::default()).unwrap());
+    fields.entry("objects").or_insert_with(|| json!([]));
+    if let Some(items) = fields.get_mut("items").and_then(Value::as_array_mut) {
+        for item in items {
+            fill_weapon_defaults(item);
+        }
+    }
+}
+
+/// Version 1 stores tiles as nested rows next to separate `width` and `height` fields.
+/// Rows that are too short are padded with empty tiles.
+fn migrate_v1(fields: &mut serde_json::Map<String, Value>) {
+    let mut dimension = |name: &str| fields.remove(name).and_then(|value| value.as_u64()).unwrap_or(0);
+    let (width, height) = (dimension("width"), dimension("height"));
+    let rows = fields.remove("tiles").unwrap_or_default();
+    let empty = serde_json::to_value(EMPTY_TILE).unwrap();
+
+    let mut runs: Vec<(u64, Value)> = Vec::new();
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let tile = rows.get(y).and_then(|row| row.get(x)).unwrap_or(&empty);
+            match runs.last_mut() {
+                Some((count, last)) if last == tile => *count += 1,
+                _ => runs.push((1, tile.clone())),
+            }
+        }
+    }
+    fields.insert("tiles".to_string(), json!({ "width": width, "height": height, "runs": runs }));
+}
+
+/// Version 2 tiles have no texture variant or rotation.
+fn migrate_v2(fields: &mut serde_json::Map<String, Value>) {
+    let Some(runs) = fields.get_mut("tiles").and_then(|tiles| tiles.get_mut("runs")).and_then(Value::as_array_mut)
+    else {
+        return;
+    };
+    for tile in runs.iter_mut().filter_map(|run| run.get_mut(1)).filter_map(Value::as_object_mut) {
+        tile.entry("variant").or_insert(json!(0));
+        tile.entry("rotation").or_insert(json!(0));
+    }
+}
+
+/// Version 3 tiles have a closed `kind` enum and a `collision` flag instead of a tileset id.
+/// Each combination maps to a tile type of the bundled tileset that plays the same, at
+/// full speed and blocking movement and bullets only if it had collision.
+fn migrate_v3(fields: &mut serde_json::Map<String, Value>) {
+    let Some(runs) = fields.get_mut("tiles").and_then(|tiles| tiles.get_mut("runs")).and_then(Value::as_array_mut)
+    else {
+        return;
+    };
+    for tile in runs.iter_mut().filter_map(|run| run.get_mut(1)).filter_map(Value::as_object_mut) {
+        let collision = tile.remove("collision").and_then(|c| c.as_bool()).unwrap_or(false);
+        let id = match (tile.remove("kind").as_ref().and_then(Value::as_str), collision) {
+            (Some("Grass"), false) => 1,
+            (Some("Rock"), true) => 2,
+            (Some("Rock"), false) => 10,
+            (Some("Grass"), true) => 11,
+            _ => 0,
+        };
+        tile.insert("id".to_string(), json!(id));
+    }
+}
+
+/// Version 4 maps have a single `tiles` grid, which becomes the ground layer.
+fn migrate_v4(fields: &mut serde_json::Map<String, Value>) {
+    let ground = fields.remove("tiles").unwrap_or_default();
+    let width = ground.get("width").and_then(Value::as_u64).unwrap_or(0);
+    let height = ground.get("height").and_then(Value::as_u64).unwrap_or(0);
+    let empty = json!({ "width": width, "height": height, "runs": [[width * height, EMPTY_TILE]] });
+    let layers: Vec<Value> = LayerKind::ALL
+        .iter()
+        .map(|&kind| {
+            let tiles = if kind == LayerKind::Ground { ground.clone() } else { empty.clone() };
+            json!({ "kind": kind, "tiles": tiles })
+        })
+        .collect();
+    fields.insert("layers".to_string(), Value::Array(layers));
+}
+
+/// Adds the default stats of the weapon's kind for any field the item is missing.
+fn fill_weapon_defaults(item: &mut Value) {
+    let Some(weapon) = item.pointer_mut("/kind/Weapon").and_then(Value::as_object_mut) else {
+        return;
+    };
+    let Some(kind) = weapon.get("weapon_kind").and_then(|kind| serde_json::from_value::<WeaponKind>(kind.clone()).ok())
+    else {
+        return;
+    };
+    if let Value::Object(defaults) = serde_json::to_value(Weapon::from_kind(&kind)).unwrap() {
+        for (field, value) in defaults {
+            weapon.entry(field).or_insert(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::ItemKind;
+    use crate::tilegrid::TileRun;
+    use crate::tileset::CollisionShape;
+
+    const V0: &str = include_str!("../tests/fixtures/maps/v0.json");
+    const V1: &str = include_str!("../tests/fixtures/maps/v1.json");
+    const V2: &str = include_str!("../tests/fixtures/maps/v2.json");
+    const V3: &str = include_str!("../tests/fixtures/maps/v3.json");
+    const V4: &str = include_str!("../tests/fixtures/maps/v4.json");
+    const V5: &str = include_str!("../tests/fixtures/maps/v5.json");
+
+    const GRASS: u16 = 1;
+    const ROCK: u16 = 2;
+    const HEDGE: u16 = 3;
+    const GRAVEL: u16 = 4;
+
+    fn load(data: &str) -> Map {
+        let tileset = Tileset::from_json(include_str!("../res/tileset.json")).unwrap();
+        Map::from_json(data, tileset).unwrap()
+    }
+
+    fn ground(map: &Map, x: usize, y: usize) -> Option<&Tile> {
+        map.layer(LayerKind::Ground).get(x, y)
+    }
+
+    fn blocks_bullets(map: &Map, x: i64, y: i64) -> bool {
+        map.tile_defs(x, y).any(|tile| tile.blocks_bullets)
+    }
+
+    #[test]
+    fn loads_unversioned_map() {
+        let map = load(V0);
+        assert_eq!(map.header, MapHeader::default());
+        assert!(map.objects.is_empty());
+        assert_eq!((map.width(), map.height()), (3, 2));
+        assert_eq!(ground(&map, 1, 0).unwrap().id, ROCK);
+
+        let ItemKind::Weapon(ref weapon) = map.items[0].kind;
+        let defaults = Weapon::from_kind(&WeaponKind::Ak47);
+        // Stats saved in the file are kept, missing ones come from the weapon kind
+        assert_eq!(weapon.spread, 10.0);
+        assert_eq!(weapon.range, defaults.range);
+        assert_eq!(weapon.ammo, defaults.ammo);
+        assert_eq!(map.items[0].id, 14142511712913326080);
+    }
+
+    #[test]
+    fn loads_v1_map() {
+        let map = load(V1);
+        assert_eq!(map.header.name, "Fixture");
+        assert_eq!(map.header.recommended_players, 4);
+        assert!(map.header.supports(GameModeKind::CaptureTheFlag));
+        assert!(!map.header.supports(GameModeKind::FreeForAll));
+        assert_eq!(map.objects.len(), 2);
+        assert_eq!(ground(&map, 2, 1).unwrap().id, ROCK);
+        assert_eq!(ground(&map, 0, 1).unwrap().id, GRASS);
+    }
+
+    #[test]
+    fn loads_v2_map() {
+        let map = load(V2);
+        assert_eq!((map.width(), map.height()), (4, 3));
+        let rocks: Vec<(usize, usize)> =
+            map.layer(LayerKind::Ground).region(0..4, 0..3).filter(|(_, _, tile)| tile.id == ROCK).map(|(x, y, _)| (x, y)).collect();
+        assert_eq!(rocks, [(1, 1), (2, 1)]);
+        assert_eq!(map.header.name, "Runs");
+    }
+
+    #[test]
+    fn loads_v3_map() {
+        let map = load(V3);
+        let tile = ground(&map, 1, 0).unwrap();
+        assert_eq!((tile.id, tile.variant, tile.rotation), (GRASS, 1, 3));
+        assert_eq!(ground(&map, 0, 0), Some(&EMPTY_TILE));
+        assert_eq!(ground(&map, 2, 0).unwrap().id, ROCK);
+
+        // Migrated tiles play as before, not like the newer gravel and hedges
+        let rock_floor = map.tile_defs(1, 1).next().unwrap();
+        assert_eq!((rock_floor.name.as_str(), rock_floor.speed, rock_floor.collision), ("Rock floor", 1.0, CollisionShape::None));
+        let grass_wall = map.tile_defs(2, 1).next().unwrap();
+        assert_eq!((grass_wall.name.as_str(), grass_wall.speed, grass_wall.collision), ("Grass wall", 1.0, CollisionShape::Full));
+        assert!(grass_wall.blocks_bullets && !grass_wall.blocks_sight);
+    }
+
+    #[test]
+    fn loads_v4_map() {
+        let map = load(V4);
+        assert_eq!(ground(&map, 0, 0).unwrap().id, 3);
+        assert!(blocks_bullets(&map, 0, 0));
+        // Ids missing from the tileset load fine and behave like empty tiles
+        assert_eq!(ground(&map, 1, 0).unwrap().id, 99);
+        assert!(!blocks_bullets(&map, 1, 0));
+        assert_eq!(map.tile_defs(-1, 0).count(), 0);
+        // Older maps only had ground tiles
+        assert_eq!(map.layer(LayerKind::Walls).runs(), [TileRun(2, EMPTY_TILE)]);
+    }
+
+    #[test]
+    fn loads_v5_map() {
+        let map = load(V5);
+        assert_eq!(map.ground_at(Vec2::new(TILE_SIZE * 2.5, 0.0)).unwrap().speed, 0.8);
+        assert!(blocks_bullets(&map, 0, 0));
+        // Overlays are only drawn, they don't block anything
+        assert_eq!(map.layer(LayerKind::Overlay).get(2, 0).unwrap().id, ROCK);
+        assert!(!blocks_bullets(&map, 2, 0));
+    }
+
+    #[test]
+    fn rejects_missing_or_mismatched_layers() {
+        let mut file: Value = serde_json::from_str(V5).unwrap();
+        file["layers"].as_array_mut().unwrap().swap(0, 1);
+        assert!(Map::from_json(&file.to_string(), Tileset::default()).is_err());
+
+        let mut file: Value = serde_json::from_str(V5).unwrap();
+        file["layers"][3]["tiles"] = json!({ "width": 1, "height": 1, "runs": [[1, EMPTY_TILE]] });
+        assert!(Map::from_json(&file.to_string(), Tileset::default()).is_err());
+    }
+
+    #[test]
+    fn resize_applies_to_every_layer() {
+        let mut map = load(V5);
+        assert!(map.resize(-1, 0, 0, 2));
+        assert!(map.layers.iter().all(|layer| (layer.tiles.width(), layer.tiles.height()) == (2, 3)));
+        assert_eq!(map.layer(LayerKind::Overlay).get(1, 0).unwrap().id, ROCK);
+        assert!(!map.resize(-2, 0, 0, 0));
+    }
+
+    #[test]
+    fn oversized_resizes_leave_the_map_alone() {
+        let mut map = load(V5);
+        map.items.push(Weapon::ak47(10.0, 10.0, false));
+        let before = map.clone();
+        assert!(!map.resize(-1, 0, 4096 * 4096, 0));
+        assert!(!map.resize(0, 4096, 4096, 0));
+        assert_eq!(map, before);
+    }
+
+    #[test]
+    fn cropping_removes_what_falls_off_the_map() {
+        let mut map = Map::new(4, 4, Tileset::default());
+        let tile = |n: f32| n * TILE_SIZE + 10.0;
+        map.items = vec![Weapon::ak47(tile(0.0), tile(2.0), false), Weapon::magnum(tile(2.0), tile(2.0), false)];
+        let line = |x1: f32, x2: f32| MapObject {
+            kind: ObjectKind::StartLine,
+            shape: ObjectShape::Line { x1, y1: tile(1.0), x2, y2: tile(1.0) },
+        };
+        map.objects = vec![line(tile(0.0), tile(2.0)), line(tile(1.0), tile(2.0)), line(tile(1.0), tile(3.0))];
+
+        assert!(map.resize(-1, 0, -1, 0));
+        assert_eq!(map.items.len(), 1);
+        assert_eq!((map.items[0].x, map.items[0].y), (tile(1.0), tile(2.0)));
+        // Only the line that was over the remaining middle tiles is kept, moved along with them
+        assert_eq!(map.objects, [line(tile(1.0) - TILE_SIZE, tile(2.0) - TILE_SIZE)]);
+
+        assert!(map.resize(1, 0, 1, 0));
+        assert_eq!(map.objects.len(), 1);
+    }
+
+    #[test]
+    fn destructible_tiles_break_after_enough_damage() {
+        const CRATE: u16 = 8;
+        let tileset = Tileset::from_json(include_str!("../res/tileset.json")).unwrap();
+        let mut map = Map::new(2, 1, tileset);
+        map.layer_mut(LayerKind::Walls).get_mut(0, 0).unwrap().id = CRATE;
+        map.layer_mut(LayerKind::Walls).get_mut(1, 0).unwrap().id = HEDGE;
+
+        assert_eq!(map.damage_tile(0, 0, 60), None);
+        let change = map.damage_tile(0, 0, 40).unwrap();
+        assert_eq!(change, TileChange { layer: LayerKind::Walls, x: 0, y: 0, tile: EMPTY_TILE });
+        assert_eq!(map.layer(LayerKind::Walls).get(0, 0), Some(&EMPTY_TILE));
+        assert!(map.tile_damage.is_empty());
+        // Nothing left that blocks bullets
+        assert_eq!(map.damage_tile(0, 0, 100), None);
+        // Hedges have no durability
+        assert_eq!(map.damage_tile(1, 0, 1000), None);
+    }
+
+    #[test]
+    fn destroyed_tiles_change_the_same_on_every_copy() {
+        let tileset = Tileset::from_json(include_str!("../res/tileset.json")).unwrap();
+        let mut map = Map::new(5, 1, tileset);
+        for x in 0..5 {
+            map.layer_mut(LayerKind::Ground).get_mut(x, 0).unwrap().id = ROCK;
+        }
+        let durability = map.tileset.get(ROCK).durability.unwrap();
+
+        let changes: Vec<TileChange> = map.damage_tile(2, 0, durability).into_iter().collect();
+        assert_eq!((changes[0].x, changes[0].tile.id), (2, GRAVEL));
+
+        let mut copy = Map::new(5, 1, Tileset::default());
+        copy.layers.clone_from(&map.layers);
+        changes.iter().for_each(|change| copy.apply_change(change));
+        assert_eq!(copy.layer(LayerKind::Ground).get(2, 0).unwrap().id, GRAVEL);
+    }
+
+    #[test]
+    fn items_are_picked_up_and_dropped_once() {
+        let mut map = load(V0);
+        let item = map.items[0].clone();
+
+        assert!(map.apply_item_change(&ItemChange::PickedUp(item.id)));
+        assert!(map.items.is_empty());
+        assert!(!map.apply_item_change(&ItemChange::PickedUp(item.id)));
+
+        assert!(map.apply_item_change(&ItemChange::Dropped(item.clone())));
+        assert!(!map.apply_item_change(&ItemChange::Dropped(item.clone())));
+        assert_eq!(map.items, vec![item]);
+    }
+
+    #[test]
+    fn same_map_loads_the_same_from_every_version() {
+        let mut v0 = load(V0);
+        let v1 = load(V1);
+        v0.items.clear();
+        assert_eq!(v0.layers, v1.layers);
+    }
+
+    #[test]
+    fn migrated_maps_round_trip() {
+        for data in [V0, V1, V2, V3, V4, V5] {
+            let map = load(data);
+            assert_eq!(load(&map.to_json()), map);
         }
     }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut file: Value = serde_json::from_str(V5).unwrap();
+        file["version"] = json!(MAP_FORMAT_VERSION + 1);
+        assert!(Map::from_json(&file.to_string(), Tileset::default()).is_err());
+    }
+
+    #[test]
+    fn bundled_map_loads() {
+        load(include_str!("../map.json"));
+    }
 }
@@ -0,0 +1,206 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::map::Map;
+use crate::tileset::Tileset;
+
+/// Folder the mapping tool browses for maps.
+pub const MAPS_DIR: &str = "maps";
+/// Where unsaved work is autosaved. Hidden, so it isn't listed among the maps.
+pub const RECOVERY_FILE: &str = "maps/.recovery.json";
+
+/// Map files in `dir` sorted by name, leaving out hidden files. Empty if `dir` doesn't exist.
+pub fn list_maps(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut maps: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+        .collect();
+    maps.sort();
+    maps
+}
+
+/// Path of a map typed in by the user. A bare name is a map in `MAPS_DIR`, with `.json`
+/// added if missing, while anything with a folder in it is used as is.
+pub fn resolve(name: &str) -> PathBuf {
+    let path = Path::new(name.trim());
+    if path.components().count() > 1 {
+        return path.to_path_buf();
+    }
+    let mut path = Path::new(MAPS_DIR).join(path);
+    if path.extension().is_none_or(|ext| ext != "json") {
+        path.as_mut_os_string().push(".json");
+    }
+    path
+}
+
+pub fn load(path: &Path, tileset: Tileset) -> Result<Map, String> {
+    let data = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    Map::from_json(&data, tileset)
+}
+
+/// Writes `map` to `path`, creating its folder if needed.
+pub fn write(path: &Path, map: &Map) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, map.to_json())
+}
+
+/// The file a map being edited belongs to, and what was last saved to it.
+pub struct Document {
+    /// `None` until a new map is first saved.
+    pub path: Option<PathBuf>,
+    saved: String,
+}
+
+impl Document {
+    pub fn new(map: &Map, path: Option<PathBuf>) -> Self {
+        Document { path, saved: map.to_json() }
+    }
+
+    /// A map restored from the recovery file, unsaved until it is saved somewhere.
+    pub fn recovered() -> Self {
+        Document { path: None, saved: String::new() }
+    }
+
+    /// Whether `map` differs from what was last saved or loaded.
+    pub fn is_dirty(&self, map: &Map) -> bool {
+        map.to_json() != self.saved
+    }
+
+    pub fn save(&mut self, map: &Map, path: PathBuf) -> io::Result<()> {
+        write(&path, map)?;
+        self.saved = map.to_json();
+        self.path = Some(path);
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        self.path.as_ref().map_or("Untitled".to_string(), |path| path.display().to_string())
+    }
+}
+
+/// The file unsaved work is autosaved to. Work an earlier session left there is kept
+/// until it is restored or discarded, rather than overwritten by the next autosave.
+pub struct Recovery {
+    path: PathBuf,
+    /// Whether the file holds an earlier session's work that hasn't been dealt with yet.
+    pending: bool,
+}
+
+impl Recovery {
+    pub fn new(path: PathBuf) -> Self {
+        let pending = path.exists();
+        Recovery { path, pending }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Writes `map` to the recovery file. Returns false without writing while an earlier
+    /// session's work is still pending.
+    pub fn autosave(&self, map: &Map) -> io::Result<bool> {
+        if self.pending {
+            return Ok(false);
+        }
+        write(&self.path, map)?;
+        Ok(true)
+    }
+
+    /// Reads back an earlier session's work. The file stays until the restored map is
+    /// saved, in case it is lost again.
+    pub fn restore(&mut self, tileset: Tileset) -> Result<Map, String> {
+        let map = load(&self.path, tileset)?;
+        self.pending = false;
+        Ok(map)
+    }
+
+    /// Throws away an earlier session's work.
+    pub fn discard(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        self.pending = false;
+    }
+
+    /// Removes this session's autosave once the work in it was saved or thrown away.
+    /// Pending work from an earlier session is left alone.
+    pub fn clear(&self) {
+        if !self.pending {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{LayerKind, Tile};
+
+    const ROCK: Tile = Tile { id: 2, variant: 0, rotation: 0 };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zone-zero-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn bare_names_go_to_the_maps_folder() {
+        assert_eq!(resolve("arena"), Path::new("maps/arena.json"));
+        assert_eq!(resolve(" arena.json "), Path::new("maps/arena.json"));
+        assert_eq!(resolve("other/arena.json"), Path::new("other/arena.json"));
+    }
+
+    #[test]
+    fn saving_round_trips_and_clears_the_dirty_state() {
+        let dir = temp_dir("maps");
+        let mut map = Map::new(4, 4, Tileset::default());
+        let mut document = Document::new(&map, None);
+        assert!(!document.is_dirty(&map));
+
+        *map.layer_mut(LayerKind::Ground).get_mut(1, 1).unwrap() = ROCK;
+        assert!(document.is_dirty(&map));
+        document.save(&map, dir.join("b.json")).unwrap();
+        assert!(!document.is_dirty(&map));
+        write(&dir.join("a.json"), &map).unwrap();
+        fs::write(dir.join(".recovery.json"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        assert_eq!(list_maps(&dir), [dir.join("a.json"), dir.join("b.json")]);
+        assert_eq!(load(&dir.join("b.json"), Tileset::default()).unwrap().layers, map.layers);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(list_maps(&dir).is_empty());
+    }
+
+    #[test]
+    fn an_earlier_session_survives_until_restored() {
+        let dir = temp_dir("recovery");
+        let path = dir.join(".recovery.json");
+        let mut lost = Map::new(4, 4, Tileset::default());
+        *lost.layer_mut(LayerKind::Ground).get_mut(0, 0).unwrap() = ROCK;
+        write(&path, &lost).unwrap();
+
+        // Restoring while the map open has unsaved changes goes through the prompt, which
+        // clears the recovery file whether the changes are saved or thrown away
+        let mut recovery = Recovery::new(path.clone());
+        let mut current = Map::new(2, 2, Tileset::default());
+        assert!(recovery.is_pending());
+        assert!(!recovery.autosave(&current).unwrap());
+        recovery.clear();
+        assert_eq!(recovery.restore(Tileset::default()).unwrap().layers, lost.layers);
+
+        *current.layer_mut(LayerKind::Ground).get_mut(1, 1).unwrap() = ROCK;
+        assert!(recovery.autosave(&current).unwrap());
+        recovery.clear();
+        assert!(!path.exists());
+
+        write(&path, &lost).unwrap();
+        let mut recovery = Recovery::new(path.clone());
+        recovery.discard();
+        assert!(!recovery.is_pending() && !path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,138 +1,679 @@
-use crate::{item::{Weapon}, map::{Map, TileKind}};
+use crate::{common::TILE_SIZE, gamemode::{GameModeKind, Team}, history::History, item::{Item, ItemKind, Weapon, WeaponKind, ITEM_SIZE}, map::{LayerKind, Map, MapObject, ObjectKind, ObjectShape, Tile, EMPTY_TILE}, map_files::{self, Document, Recovery, MAPS_DIR, RECOVERY_FILE}, paint, render, resources::Resources, tilegrid::MAX_TILES, tileset::Tileset};
 use macroquad::prelude::*;
 use macroquad::rand::*;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{fs, io, process};
+use std::process;
+
+/// Size of the map the tool starts with, and the default for new ones.
+const DEFAULT_MAP_SIZE: (u32, u32) = (256, 256);
+/// Screen pixels per world pixel when the tool opens, and how far it can zoom either way.
+const DEFAULT_ZOOM: f32 = 0.5;
+const MIN_ZOOM: f32 = 0.05;
+const MAX_ZOOM: f32 = 4.0;
+/// Zoom factor of one mouse wheel notch.
+const ZOOM_STEP: f32 = 1.2;
+/// Keyboard panning speed, in screen pixels per second.
+const PAN_SPEED: f32 = 800.0;
+/// Grid lines closer than this many screen pixels are not drawn.
+const MIN_GRID_SPACING: f32 = 6.0;
+/// Seconds between checks for unsaved changes, which also autosave them.
+const DIRTY_CHECK_INTERVAL: f64 = 1.0;
+/// Seconds between writes of unsaved changes to the recovery file.
+const AUTOSAVE_INTERVAL: f64 = 60.0;
 
-const TILE_SIZE: f32 = 32.0;
-const MAP_WIDTH: u32 = 256;
-const MAP_HEIGHT: u32 = 256;
 #[derive(PartialEq)]
 enum DrawMode {
     Tiles,
     Items,
+    Objects,
+}
+
+#[derive(PartialEq)]
+enum TileTool {
+    Brush,
+    Rect,
+    FilledRect,
+    Fill,
+}
+
+#[derive(PartialEq)]
+enum ItemTool {
+    Place,
+    Select,
+}
+
+#[derive(PartialEq)]
+enum ShapeMode {
+    Line,
+    Rect,
+}
+
+/// File actions that throw away unsaved changes, so they ask first.
+enum FileAction {
+    New(u32, u32),
+    Open(PathBuf),
+    Recover,
+    Quit,
 }
 
 #[macroquad::main("Mapping Tool")]
 pub async fn main() {
     srand(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64);
-    let mut map: Map;
-    
-
-    let mut buf = String::new();
-    println!("Load map? y/n");
-    io::stdin().read_line(&mut buf).unwrap();
-    match buf.trim() {
-        "y" => {
-            map = serde_json::from_str(&fs::read_to_string("map.json").unwrap()).unwrap();
-        }
-        &_ => { 
-            map = Map::new(MAP_WIDTH, MAP_HEIGHT);
+    let tileset = match Tileset::load() {
+        Ok(tileset) => tileset,
+        Err(e) => {
+            eprintln!("Failed to load tileset: {}", e);
+            process::exit(1);
         }
-    }
+    };
+    let mut map = Map::new(DEFAULT_MAP_SIZE.0, DEFAULT_MAP_SIZE.1, tileset);
+    let mut document = Document::new(&map, None);
+
+    let resources = Resources::load().await;
 
-    let mut tile_kind = TileKind::Grass;
+    let mut tile_id = map.tileset.tiles.iter().find(|tile| tile.id != EMPTY_TILE.id).map_or(EMPTY_TILE.id, |tile| tile.id);
+    let mut tile_variant: u8 = 0;
+    let mut tile_rotation: u8 = 0;
+    let mut random_rotation = false;
+    let mut tile_tool = TileTool::Brush;
+    let mut brush_size: u32 = 1;
+    // Tile the brush was on last frame, to paint the tiles skipped by fast mouse moves
+    let mut last_brush_tile: Option<(i64, i64)> = None;
+    let mut tile_drag_start: Option<(i64, i64)> = None;
+    let mut item_tool = ItemTool::Place;
+    let mut item_kind = WeaponKind::Ak47;
+    let mut selected_item: Option<u64> = None;
+    // Where the dragged item was picked up from, and where in it the mouse grabbed it
+    let mut item_drag: Option<(Vec2, Vec2)> = None;
+    let mut active_layer = LayerKind::Ground;
+    // Indexed like `LayerKind::ALL`
+    let mut layer_visible = [true; 4];
     let mut drawing_mode = DrawMode::Tiles;
-    let mut can_collide = true;
+    let mut object_kind = ObjectKind::StartLine;
+    let mut shape_mode = ShapeMode::Line;
+    let mut drag_start: Option<Vec2> = None;
+    // Tiles to add (or remove, if negative) on the left, top, right and bottom
+    let mut resize_by = [0i32; 4];
+    let mut history = History::default();
+    // Whether an egui text field has the keyboard, so shortcuts are left alone
+    let mut typing = false;
+    // Whether the mouse is on an egui window, so clicking its buttons doesn't paint the map
+    let mut over_ui = false;
+    // The window is closed through the unsaved changes prompt instead
+    prevent_quit();
+    let mut browser_open = true;
+    let mut listing = map_files::list_maps(Path::new(MAPS_DIR));
+    // Map name or path typed in the file browser
+    let mut file_name = String::new();
+    let mut new_size = DEFAULT_MAP_SIZE;
+    // Result of the last save or load, shown to the user
+    let mut file_status = String::new();
+    let mut requested: Option<FileAction> = None;
+    // Action waiting on the unsaved changes prompt
+    let mut unsaved_prompt: Option<FileAction> = None;
+    // Refreshed every `DIRTY_CHECK_INTERVAL`, as comparing with the saved map isn't free
+    let mut dirty = false;
+    let mut last_dirty_check = get_time();
+    let mut last_autosave = get_time();
+    let mut recovery = Recovery::new(PathBuf::from(RECOVERY_FILE));
 
-    let mut cx = 0.0;
-    let mut cy = 0.0;
+    let mut show_grid = true;
+    let mut camera_target = vec2(map.width() as f32, map.height() as f32) * TILE_SIZE / 2.0;
+    let mut zoom = DEFAULT_ZOOM;
+    // Mouse position last frame while panning with the middle button
+    let mut pan_from: Option<Vec2> = None;
     loop {
         clear_background(WHITE);
-        let camera = Camera2D {
-            target: vec2(cx, cy),
-            zoom: vec2(1.0 / screen_width() * 1.0, 1.0 / screen_height() * 1.0),
-            ..Default::default()
-        };
-        set_camera(&camera);
-        if is_key_down(KeyCode::W) {
-            cy -= 1.0;
-        }
-        if is_key_down(KeyCode::S) {
-            cy += 1.0;
-        }
-        if is_key_down(KeyCode::A) {
-            cx -= 1.0;
-        }
-        if is_key_down(KeyCode::D) {
-            cx += 1.0;
-        }
-        for (y, row) in map.tiles.iter().enumerate() {
-            for (x, tile) in row.iter().enumerate() {
-                match tile.kind {
-                    TileKind::Grass => draw_rectangle(
-                        x as f32 * TILE_SIZE,
-                        y as f32 * TILE_SIZE,
-                        TILE_SIZE,
-                        TILE_SIZE,
-                        GREEN,
-                    ),
-                    TileKind::Rock => draw_rectangle(
-                        x as f32 * TILE_SIZE,
-                        y as f32 * TILE_SIZE,
-                        TILE_SIZE,
-                        TILE_SIZE,
-                        BLACK,
-                    ),
-                    TileKind::Empty => {}
+        let mouse_screen = vec2(mouse_position().0, mouse_position().1);
+        let wheel = mouse_wheel().1;
+        if wheel != 0.0 && !over_ui {
+            // Zooms around the cursor, keeping the point under it in place
+            let before = editor_camera(camera_target, zoom).screen_to_world(mouse_screen);
+            zoom = (zoom * ZOOM_STEP.powf(wheel.signum())).clamp(MIN_ZOOM, MAX_ZOOM);
+            camera_target += before - editor_camera(camera_target, zoom).screen_to_world(mouse_screen);
+        }
+        if is_mouse_button_pressed(MouseButton::Middle) && !over_ui {
+            pan_from = Some(mouse_screen);
+        }
+        if !is_mouse_button_down(MouseButton::Middle) {
+            pan_from = None;
+        }
+        if let Some(from) = pan_from {
+            let camera = editor_camera(camera_target, zoom);
+            camera_target -= camera.screen_to_world(mouse_screen) - camera.screen_to_world(from);
+            pan_from = Some(mouse_screen);
+        }
+        if !typing {
+            let mut pan = Vec2::ZERO;
+            for (key, direction) in [(KeyCode::W, Vec2::NEG_Y), (KeyCode::S, Vec2::Y), (KeyCode::A, Vec2::NEG_X), (KeyCode::D, Vec2::X)] {
+                if is_key_down(key) {
+                    pan += direction;
                 }
             }
+            camera_target += pan * PAN_SPEED * get_frame_time() / zoom;
+        }
+        let camera = editor_camera(camera_target, zoom);
+        set_camera(&camera);
+        let mouse_world = camera.screen_to_world(mouse_screen);
+        let hovered_tile = paint::tile_at(mouse_world);
+
+        for kind in LayerKind::ALL {
+            if layer_visible[kind as usize] {
+                render::draw_layer(&map, kind, &camera, &resources, WHITE);
+            }
+        }
+        if show_grid && TILE_SIZE * zoom >= MIN_GRID_SPACING {
+            draw_grid(&map, &camera, zoom);
+        }
+        let (map_width, map_height) = (map.width() as f32 * TILE_SIZE, map.height() as f32 * TILE_SIZE);
+        draw_rectangle_lines(0.0, 0.0, map_width, map_height, 3.0 / zoom, RED);
+        for object in &map.objects {
+            render::draw_map_object(object);
+        }
+        for item in &map.items {
+            let ItemKind::Weapon(ref weapon) = item.kind;
+            draw_texture(resources.weapon_texture(&weapon.weapon_kind), item.x, item.y, WHITE);
+            if selected_item == Some(item.id) {
+                draw_rectangle_lines(item.x, item.y, ITEM_SIZE, ITEM_SIZE, 2.0, YELLOW);
+            }
         }
         egui_macroquad::ui(|egui_ctx| {
+            typing = egui_ctx.wants_keyboard_input();
+            over_ui = egui_ctx.is_pointer_over_area() || egui_ctx.is_using_pointer();
             egui::Window::new("Mapping Tool").show(egui_ctx, |ui| {
+                ui.label(format!("{}{}", document.name(), if dirty { " (unsaved)" } else { "" }));
+                ui.horizontal(|ui| {
+                    if ui.button("Save (Ctrl+S)").clicked() {
+                        match document.path.clone() {
+                            Some(path) => dirty &= !save_map(&mut document, &map, path, &recovery, &mut file_status),
+                            None => browser_open = true,
+                        }
+                    }
+                    if ui.button("Maps (Ctrl+O)").clicked() {
+                        browser_open = true;
+                    }
+                    if ui.button("Quit").clicked() {
+                        requested = Some(FileAction::Quit);
+                    }
+                });
+                if !file_status.is_empty() {
+                    ui.label(&file_status);
+                }
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(history.can_undo(), egui::Button::new("Undo (Ctrl+Z)")).clicked() {
+                        history.undo(&mut map);
+                    }
+                    if ui.add_enabled(history.can_redo(), egui::Button::new("Redo (Ctrl+Y)")).clicked() {
+                        history.redo(&mut map);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut show_grid, "Grid (G)");
+                    ui.label(format!("Zoom {:.0}%", zoom * 100.0));
+                });
+                let (x, y) = hovered_tile;
+                let inside = x >= 0 && y >= 0 && x < map.width() as i64 && y < map.height() as i64;
+                ui.label(format!("Tile {}, {}{}", x, y, if inside { "" } else { " (outside the map)" }));
                 ui.heading("Current mode");
                 ui.radio_value(&mut drawing_mode, DrawMode::Tiles, "Tiles");
                 ui.radio_value(&mut drawing_mode, DrawMode::Items, "Items");
+                ui.radio_value(&mut drawing_mode, DrawMode::Objects, "Objects");
+                ui.heading("Layers");
+                for kind in LayerKind::ALL {
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut active_layer, kind, format!("{:?}", kind));
+                        ui.checkbox(&mut layer_visible[kind as usize], "Visible");
+                    });
+                }
+                ui.heading("Tile tool");
+                ui.radio_value(&mut tile_tool, TileTool::Brush, "Brush");
+                ui.add(egui::Slider::new(&mut brush_size, 1..=9).text("Brush size"));
+                ui.radio_value(&mut tile_tool, TileTool::Rect, "Rectangle");
+                ui.radio_value(&mut tile_tool, TileTool::FilledRect, "Filled rectangle");
+                ui.radio_value(&mut tile_tool, TileTool::Fill, "Flood fill");
                 ui.heading("Drawing Mode");
-                ui.checkbox(&mut can_collide, "Can collide");
-                ui.radio_value(&mut tile_kind, TileKind::Rock, "Rock");
-                ui.radio_value(&mut tile_kind, TileKind::Grass, "Grass");
-                ui.radio_value(&mut tile_kind, TileKind::Empty, "Empty");
-
-                if ui.button("Save and quit").clicked() {
-                    fs::write("map.json", serde_json::to_string(&map).unwrap()).unwrap();
-                    process::exit(0);
+                for tile in &map.tileset.tiles {
+                    ui.radio_value(&mut tile_id, tile.id, &tile.name);
+                }
+                ui.add(egui::Slider::new(&mut tile_variant, 0..=7).text("Variant"));
+                ui.add(egui::Slider::new(&mut tile_rotation, 0..=3).text("Rotation (R)"));
+                ui.checkbox(&mut random_rotation, "Random rotation");
+                ui.heading("Items");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut item_tool, ItemTool::Place, "Place");
+                    ui.radio_value(&mut item_tool, ItemTool::Select, "Select (Del removes)");
+                });
+                for kind in WeaponKind::ALL {
+                    let label = format!("{:?}", kind);
+                    ui.radio_value(&mut item_kind, kind, label);
+                }
+                ui.heading("Objects");
+                ui.radio_value(&mut object_kind, ObjectKind::StartLine, "Start line");
+                ui.radio_value(&mut object_kind, ObjectKind::FinishLine, "Finish line");
+                ui.radio_value(&mut object_kind, ObjectKind::FlagBase(Team::Red), "Red flag base");
+                ui.radio_value(&mut object_kind, ObjectKind::FlagBase(Team::Blue), "Blue flag base");
+                ui.radio_value(&mut shape_mode, ShapeMode::Line, "Line");
+                ui.radio_value(&mut shape_mode, ShapeMode::Rect, "Rect");
+            });
+            egui::Window::new("Maps").open(&mut browser_open).show(egui_ctx, |ui| {
+                ui.heading("New map");
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut new_size.0).range(1..=4096));
+                    ui.label("x");
+                    ui.add(egui::DragValue::new(&mut new_size.1).range(1..=4096));
+                    if ui.button("New").clicked() {
+                        requested = Some(FileAction::New(new_size.0, new_size.1));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.heading(format!("In {}/", MAPS_DIR));
+                    if ui.button("Refresh").clicked() {
+                        listing = map_files::list_maps(Path::new(MAPS_DIR));
+                    }
+                });
+                egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                    for path in &listing {
+                        let name = path.file_name().unwrap_or_default().to_string_lossy();
+                        let label = ui.selectable_label(document.path.as_ref() == Some(path), name);
+                        if label.clicked() {
+                            file_name = path.display().to_string();
+                        }
+                        if label.double_clicked() {
+                            requested = Some(FileAction::Open(path.clone()));
+                        }
+                    }
+                });
+                ui.label("Map name, or a path to a map file anywhere");
+                ui.text_edit_singleline(&mut file_name);
+                let path = map_files::resolve(&file_name);
+                ui.horizontal(|ui| {
+                    let named = !file_name.trim().is_empty();
+                    if ui.add_enabled(named, egui::Button::new("Open")).clicked() {
+                        requested = Some(FileAction::Open(path.clone()));
+                    }
+                    if ui.add_enabled(named, egui::Button::new("Save as")).clicked()
+                        && save_map(&mut document, &map, path.clone(), &recovery, &mut file_status)
+                    {
+                        dirty = false;
+                        listing = map_files::list_maps(Path::new(MAPS_DIR));
+                    }
+                });
+                if path.exists() && document.path.as_ref() != Some(&path) {
+                    ui.label(format!("Saving as {} replaces it", path.display()));
+                }
+                if recovery.is_pending() {
+                    ui.horizontal(|ui| {
+                        if ui.button("Restore autosaved work").clicked() {
+                            requested = Some(FileAction::Recover);
+                        }
+                        if ui.button("Discard it").clicked() {
+                            recovery.discard();
+                        }
+                    });
+                }
+            });
+            if unsaved_prompt.is_some() {
+                egui::Window::new("Unsaved changes").collapsible(false).show(egui_ctx, |ui| {
+                    ui.label(format!("{} has unsaved changes.", document.name()));
+                    ui.horizontal(|ui| {
+                        let save = egui::Button::new("Save");
+                        if ui.add_enabled(document.path.is_some(), save).on_disabled_hover_text("Use Save as first").clicked()
+                            && let Some(path) = document.path.clone()
+                            && save_map(&mut document, &map, path, &recovery, &mut file_status)
+                        {
+                            requested = unsaved_prompt.take();
+                        }
+                        if ui.button("Don't save").clicked() {
+                            // Taken for the action without asking again
+                            document = Document::new(&map, document.path.take());
+                            recovery.clear();
+                            requested = unsaved_prompt.take();
+                        }
+                        if ui.button("Cancel").clicked() {
+                            unsaved_prompt = None;
+                        }
+                    });
+                });
+            }
+            egui::Window::new("Map size").default_open(false).show(egui_ctx, |ui| {
+                ui.label(format!("{} x {} tiles", map.width(), map.height()));
+                ui.label("Tiles to add on each side, negative to crop");
+                // From cropping all but one row or column to the most that fits on its own
+                let (width, height) = (map.width() as i64, map.height() as i64);
+                let horizontal = 1 - width..=MAX_TILES as i64 / height - width;
+                let vertical = 1 - height..=MAX_TILES as i64 / width - height;
+                let ranges = [horizontal.clone(), vertical.clone(), horizontal, vertical];
+                for ((amount, range), side) in resize_by.iter_mut().zip(ranges).zip(["Left", "Top", "Right", "Bottom"]) {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(amount).range(range));
+                        ui.label(side);
+                    });
+                }
+                if ui.button("Resize").clicked() {
+                    let [left, top, right, bottom] = resize_by;
+                    if map.resize(left, top, right, bottom) {
+                        resize_by = [0; 4];
+                        // Tile positions in the history no longer match the map
+                        history.clear();
+                    } else {
+                        eprintln!("Can't crop the map down to nothing or grow it over {} tiles", MAX_TILES);
+                    }
+                }
+            });
+            egui::Window::new("Map info").default_open(false).show(egui_ctx, |ui| {
+                let header = &mut map.header;
+                ui.label("Name");
+                ui.text_edit_singleline(&mut header.name);
+                ui.label("Author");
+                ui.text_edit_singleline(&mut header.author);
+                ui.label("Description");
+                ui.text_edit_multiline(&mut header.description);
+                ui.add(egui::Slider::new(&mut header.recommended_players, 0..=32).text("Recommended players"));
+                ui.label("Made for (none checked means any mode)");
+                for (mode, label) in [
+                    (GameModeKind::FreeForAll, "Free for all"),
+                    (GameModeKind::TeamDeathmatch, "Team deathmatch"),
+                    (GameModeKind::CaptureTheFlag, "Capture the flag"),
+                ] {
+                    let mut supported = header.modes.contains(&mode);
+                    if ui.checkbox(&mut supported, label).changed() {
+                        if supported {
+                            header.modes.push(mode);
+                        } else {
+                            header.modes.retain(|m| *m != mode);
+                        }
+                    }
                 }
             });
         });
         egui_macroquad::draw();
+        let ctrl = is_key_down(KeyCode::LeftControl) || is_key_down(KeyCode::RightControl);
+        let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        if !typing && !ctrl && is_key_pressed(KeyCode::G) {
+            show_grid = !show_grid;
+        }
+        if is_quit_requested() {
+            requested = Some(FileAction::Quit);
+        }
+        if ctrl && !typing {
+            if is_key_pressed(KeyCode::S) {
+                match document.path.clone() {
+                    Some(path) => dirty &= !save_map(&mut document, &map, path, &recovery, &mut file_status),
+                    None => {
+                        browser_open = true;
+                        file_status = "Choose a name to save the map as".to_string();
+                    }
+                }
+            } else if is_key_pressed(KeyCode::O) {
+                browser_open = true;
+            } else if is_key_pressed(KeyCode::Z) && !shift {
+                history.undo(&mut map);
+            } else if is_key_pressed(KeyCode::Y) || (is_key_pressed(KeyCode::Z) && shift) {
+                history.redo(&mut map);
+            }
+        }
         match drawing_mode {
+            _ if over_ui && drag_start.is_none() && tile_drag_start.is_none() && item_drag.is_none() => {}
             DrawMode::Tiles => {
-                if is_mouse_button_down(MouseButton::Left) || is_mouse_button_down(MouseButton::Right) {
-                    let mouse_screen = vec2(mouse_position().0, mouse_position().1);
-                    let mouse_world = camera.screen_to_world(mouse_screen);
-
-                    let x = (mouse_world.x / TILE_SIZE).floor() as usize;
-                    let y = (mouse_world.y / TILE_SIZE).floor() as usize;
-
-                    if x < MAP_WIDTH as usize && y < MAP_HEIGHT as usize {
-                        match tile_kind {
-                            TileKind::Rock => {
-                                map.tiles[y][x].kind = TileKind::Rock;
-                                map.tiles[y][x].collision = can_collide;
-                            }
-                            TileKind::Grass => {
-                                map.tiles[y][x].kind = TileKind::Grass;
-                                map.tiles[y][x].collision = can_collide;
-                            }
-                            TileKind::Empty => {
-                                map.tiles[y][x].kind = TileKind::Empty;
-                                map.tiles[y][x].collision = false;
+                if !typing && is_key_pressed(KeyCode::R) {
+                    tile_rotation = (tile_rotation + 1) % 4;
+                }
+                let hovered = hovered_tile;
+                let held = is_mouse_button_down(MouseButton::Left) || is_mouse_button_down(MouseButton::Right);
+                let pressed = is_mouse_button_pressed(MouseButton::Left) || is_mouse_button_pressed(MouseButton::Right);
+                let tile = Tile { id: tile_id, variant: tile_variant, rotation: tile_rotation };
+                let mut painted: Vec<(i64, i64)> = Vec::new();
+                match tile_tool {
+                    TileTool::Brush => {
+                        if held {
+                            let from = last_brush_tile.unwrap_or(hovered);
+                            for center in paint::line(from, hovered) {
+                                painted.extend(paint::brush(center, brush_size));
                             }
+                            last_brush_tile = Some(hovered);
+                        }
+                        for (x, y) in paint::brush(hovered, brush_size) {
+                            draw_tile_outline(x, y, GRAY);
+                        }
+                    }
+                    TileTool::Rect | TileTool::FilledRect => {
+                        if pressed {
+                            tile_drag_start = Some(hovered);
+                        }
+                        let filled = tile_tool == TileTool::FilledRect;
+                        let area = paint::rect(tile_drag_start.unwrap_or(hovered), hovered, filled);
+                        for &(x, y) in &area {
+                            draw_tile_outline(x, y, GRAY);
+                        }
+                        if !held && tile_drag_start.take().is_some() {
+                            painted = area;
                         }
                     }
+                    TileTool::Fill => {
+                        if pressed && hovered.0 >= 0 && hovered.1 >= 0 {
+                            let start = (hovered.0 as usize, hovered.1 as usize);
+                            let region = paint::flood_fill(map.layer(active_layer), start);
+                            painted = region.into_iter().map(|(x, y)| (x as i64, y as i64)).collect();
+                        }
+                        draw_tile_outline(hovered.0, hovered.1, GRAY);
+                    }
+                }
+                for (x, y) in painted {
+                    paint_tile(&mut history, &mut map, active_layer, (x, y), tile, random_rotation);
                 }
             },
             DrawMode::Items => {
+                // Drawn last, so on top
+                let hovered = map.items.iter().rev().find(|item| item_rect(item).contains(mouse_world)).map(|item| item.id);
+                if let Some(item) = map.items.iter().find(|item| Some(item.id) == hovered) {
+                    draw_rectangle_lines(item.x, item.y, ITEM_SIZE, ITEM_SIZE, 1.0, GRAY);
+                }
 
-                let mouse_screen = vec2(mouse_position().0, mouse_position().1);
-                let mouse_world = camera.screen_to_world(mouse_screen);
+                match item_tool {
+                    ItemTool::Place => {
+                        if is_mouse_button_pressed(MouseButton::Left) {
+                            let corner = mouse_world - ITEM_SIZE / 2.0;
+                            let item = Weapon::item_of_kind(&item_kind, corner.x, corner.y, false);
+                            history.edit_items(&mut map, |items| items.push(item));
+                        }
+                    }
+                    ItemTool::Select => {
+                        if is_mouse_button_pressed(MouseButton::Left) {
+                            selected_item = hovered;
+                            item_drag = map
+                                .items
+                                .iter()
+                                .find(|item| Some(item.id) == hovered)
+                                .map(|item| (vec2(item.x, item.y), mouse_world - vec2(item.x, item.y)));
+                        }
+                        if let Some((origin, grab)) = item_drag {
+                            let position = mouse_world - grab;
+                            let dragged = map.items.iter_mut().find(|item| Some(item.id) == selected_item);
+                            match dragged {
+                                Some(item) if is_mouse_button_down(MouseButton::Left) => (item.x, item.y) = (position.x, position.y),
+                                Some(item) => {
+                                    // Recorded as a single move from where the drag started
+                                    (item.x, item.y) = (origin.x, origin.y);
+                                    let id = item.id;
+                                    history.edit_items(&mut map, |items| {
+                                        if let Some(item) = items.iter_mut().find(|item| item.id == id) {
+                                            (item.x, item.y) = (position.x, position.y);
+                                        }
+                                    });
+                                    item_drag = None;
+                                }
+                                None => item_drag = None,
+                            }
+                        }
+                    }
+                }
+                let delete = if is_mouse_button_pressed(MouseButton::Right) {
+                    hovered
+                } else if !typing && (is_key_pressed(KeyCode::Delete) || is_key_pressed(KeyCode::Backspace)) {
+                    selected_item
+                } else {
+                    None
+                };
+                if let Some(id) = delete {
+                    history.edit_items(&mut map, |items| items.retain(|item| item.id != id));
+                }
+            }
+            DrawMode::Objects => {
 
                 if is_mouse_button_pressed(MouseButton::Left) {
-                    map.items.push(Weapon::ak47(mouse_world.x, mouse_world.y, false));
+                    drag_start = Some(mouse_world);
+                }
+                if let Some(start) = drag_start {
+                    let shape = match shape_mode {
+                        ShapeMode::Line => ObjectShape::Line { x1: start.x, y1: start.y, x2: mouse_world.x, y2: mouse_world.y },
+                        ShapeMode::Rect => {
+                            let min = start.min(mouse_world);
+                            let size = (start - mouse_world).abs();
+                            ObjectShape::Rect { x: min.x, y: min.y, w: size.x, h: size.y }
+                        }
+                    };
+                    let object = MapObject { kind: object_kind.clone(), shape };
+                    render::draw_map_object(&object);
+
+                    if is_mouse_button_released(MouseButton::Left) {
+                        history.edit_objects(&mut map, |objects| objects.push(object));
+                        drag_start = None;
+                    }
+                }
+                if is_mouse_button_pressed(MouseButton::Right) {
+                    history.edit_objects(&mut map, |objects| objects.retain(|object| !object.shape.near(mouse_world, 8.0)));
+                }
+            }
+        }
+        // Everything done while a button was held is undone together
+        if !is_mouse_button_down(MouseButton::Left) && !is_mouse_button_down(MouseButton::Right) {
+            history.end_stroke();
+            last_brush_tile = None;
+        }
+
+        if get_time() - last_dirty_check >= DIRTY_CHECK_INTERVAL {
+            last_dirty_check = get_time();
+            dirty = document.is_dirty(&map);
+            if dirty && get_time() - last_autosave >= AUTOSAVE_INTERVAL {
+                last_autosave = get_time();
+                match recovery.autosave(&map) {
+                    Ok(true) => file_status = format!("Autosaved to {}", RECOVERY_FILE),
+                    Ok(false) => file_status = "Not autosaving until the earlier autosaved work is restored or discarded".to_string(),
+                    Err(e) => file_status = format!("Failed to autosave: {}", e),
+                }
+            }
+        }
+        if let Some(action) = requested.take() {
+            if document.is_dirty(&map) {
+                unsaved_prompt = Some(action);
+            } else {
+                let recovering = matches!(action, FileAction::Recover);
+                let opened = match action {
+                    FileAction::New(width, height) => Ok((Map::new(width, height, map.tileset.clone()), None)),
+                    FileAction::Open(path) => match map_files::load(&path, map.tileset.clone()) {
+                        Ok(opened) => Ok((opened, Some(path))),
+                        Err(e) => Err(format!("Failed to open {}: {}", path.display(), e)),
+                    },
+                    FileAction::Recover => recovery
+                        .restore(map.tileset.clone())
+                        .map(|recovered| (recovered, None))
+                        .map_err(|e| format!("Failed to restore autosaved work: {}", e)),
+                    FileAction::Quit => process::exit(0),
+                };
+                match opened {
+                    Ok((opened, path)) => {
+                        map = opened;
+                        // Restored work stays unsaved until it is saved somewhere
+                        document = if recovering { Document::recovered() } else { Document::new(&map, path) };
+                        dirty = recovering;
+                        file_status.clear();
+                        // Whatever was being edited is gone
+                        history.clear();
+                        selected_item = None;
+                        item_drag = None;
+                        drag_start = None;
+                        tile_drag_start = None;
+                        camera_target = vec2(map.width() as f32, map.height() as f32) * TILE_SIZE / 2.0;
+                    }
+                    Err(e) => file_status = e,
                 }
             }
         }
         next_frame().await;
     }
 }
+
+/// Paints `tile` at a tile position of `layer`, ignoring positions outside the map. With
+/// `random_rotation` the rotation is rolled instead, only once per repainted tile so
+/// holding the brush over it doesn't keep spinning it.
+fn paint_tile(history: &mut History, map: &mut Map, layer: LayerKind, (x, y): (i64, i64), tile: Tile, random_rotation: bool) {
+    if x < 0 || y < 0 {
+        return;
+    }
+    let (x, y) = (x as usize, y as usize);
+    let Some(&current) = map.layer(layer).get(x, y) else {
+        return;
+    };
+    let rotation = if !random_rotation {
+        tile.rotation
+    } else if current.id != tile.id || current.variant != tile.variant {
+        gen_range(0, 4)
+    } else {
+        current.rotation
+    };
+    history.set_tile(map, layer, x, y, Tile { rotation, ..tile });
+}
+
+fn editor_camera(target: Vec2, zoom: f32) -> Camera2D {
+    Camera2D {
+        target,
+        zoom: vec2(2.0 * zoom / screen_width(), 2.0 * zoom / screen_height()),
+        ..Default::default()
+    }
+}
+
+/// Draws the lines between the tiles of the map that are on screen, a screen pixel thick.
+fn draw_grid(map: &Map, camera: &Camera2D, zoom: f32) {
+    let corners = [camera.screen_to_world(Vec2::ZERO), camera.screen_to_world(vec2(screen_width(), screen_height()))];
+    let (min, max) = (corners[0].min(corners[1]), corners[0].max(corners[1]));
+    let (width, height) = (map.width() as f32, map.height() as f32);
+    let first = (min / TILE_SIZE).floor().max(Vec2::ZERO);
+    let last = (max / TILE_SIZE).ceil().min(vec2(width, height));
+    let color = Color::new(0.0, 0.0, 0.0, 0.25);
+    let top = first.y * TILE_SIZE;
+    let bottom = last.y * TILE_SIZE;
+    for x in first.x as u32..=last.x as u32 {
+        let x = x as f32 * TILE_SIZE;
+        draw_line(x, top, x, bottom, 1.0 / zoom, color);
+    }
+    let left = first.x * TILE_SIZE;
+    let right = last.x * TILE_SIZE;
+    for y in first.y as u32..=last.y as u32 {
+        let y = y as f32 * TILE_SIZE;
+        draw_line(left, y, right, y, 1.0 / zoom, color);
+    }
+}
+
+/// Saves to `path`, telling the user how it went. Saved work no longer needs recovering.
+fn save_map(document: &mut Document, map: &Map, path: PathBuf, recovery: &Recovery, status: &mut String) -> bool {
+    match document.save(map, path) {
+        Ok(()) => {
+            *status = format!("Saved {}", document.name());
+            recovery.clear();
+            true
+        }
+        Err(e) => {
+            *status = format!("Failed to save: {}", e);
+            false
+        }
+    }
+}
+
+fn item_rect(item: &Item) -> Rect {
+    Rect::new(item.x, item.y, ITEM_SIZE, ITEM_SIZE)
+}
+
+fn draw_tile_outline(x: i64, y: i64, color: Color) {
+    draw_rectangle_lines(x as f32 * TILE_SIZE, y as f32 * TILE_SIZE, TILE_SIZE, TILE_SIZE, 1.0, color);
+}
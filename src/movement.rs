@@ -0,0 +1,409 @@
+use std::f32::consts::FRAC_PI_2;
+
+use macroquad::math::{BVec2, Rect, Vec2};
+
+use crate::common::TILE_SIZE;
+use crate::map::{LayerKind, Map};
+
+pub const PLAYER_WIDTH: f32 = 50.0;
+pub const PLAYER_HEIGHT: f32 = 50.0;
+/// Walking speed on normal ground, in pixels per second along each axis.
+pub const PLAYER_SPEED: f32 = 300.0;
+/// Where players appear after dying or a map change.
+pub const SPAWN_POINT: Vec2 = Vec2::ZERO;
+/// Tile `grip` is the share of the velocity change made in one step at this rate.
+const GRIP_RATE: f32 = 60.0;
+/// Longest distance moved at once when the server replays a move, so it can't skip over
+/// thin walls.
+const REPLAY_STEP: f32 = TILE_SIZE / 4.0;
+/// Distance a reported position may be off from the replayed one before it is corrected.
+const CORRECTION_TOLERANCE: f32 = 2.0;
+/// Seconds of movement a player can save up, absorbing packets that arrive in bursts.
+const MOVE_BUDGET_TIME: f32 = 0.5;
+
+/// Position and velocity of a player, stepped the same way on every client.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Body {
+    pub position: Vec2,
+    pub velocity: Vec2,
+}
+
+impl Body {
+    /// Moves the body for `dt` seconds while `input` (-1 to 1 on each axis) is held, following
+    /// the ground it stands on.
+    pub fn step(&mut self, map: &Map, input: Vec2, dt: f32) {
+        let (speed, grip) = map.ground_at(self.position).map_or((1.0, 1.0), |tile| (tile.speed, tile.grip));
+        let wanted = input * PLAYER_SPEED * speed;
+        let blend = 1.0 - (1.0 - grip.clamp(0.0, 1.0)).powf(dt * GRIP_RATE);
+        self.velocity += (wanted - self.velocity) * blend;
+
+        let (position, blocked) = slide(map, self.position, self.velocity * dt);
+        self.position = position;
+        if blocked.x {
+            self.velocity.x = 0.0;
+        }
+        if blocked.y {
+            self.velocity.y = 0.0;
+        }
+    }
+}
+
+pub fn body_rect(position: Vec2) -> Rect {
+    Rect::new(position.x - PLAYER_WIDTH / 2.0, position.y - PLAYER_HEIGHT / 2.0, PLAYER_WIDTH, PLAYER_HEIGHT)
+}
+
+/// Like `Rect::overlaps`, but rects that only share an edge don't count, so players can
+/// walk along walls.
+fn overlaps(a: &Rect, b: &Rect) -> bool {
+    a.left() < b.right() && a.right() > b.left() && a.top() < b.bottom() && a.bottom() > b.top()
+}
+
+struct Solid {
+    rect: Rect,
+    /// Direction a one-way tile lets players through in.
+    one_way: Option<Vec2>,
+}
+
+impl Solid {
+    /// Whether moving by `delta` from the area `before` runs into the tile. One-way tiles let
+    /// through players moving their way or already inside them.
+    fn stops(&self, before: &Rect, delta: Vec2) -> bool {
+        match self.one_way {
+            None => true,
+            Some(pass) => !overlaps(before, &self.rect) && delta.dot(pass) <= 0.0,
+        }
+    }
+}
+
+/// Collision areas of the gameplay tiles overlapping `area`.
+fn solids_in(map: &Map, area: Rect) -> Vec<Solid> {
+    LayerKind::GAMEPLAY
+        .iter()
+        .flat_map(|&kind| map.tiles_in(kind, area))
+        .filter_map(|(x, y, tile)| {
+            let def = map.tileset.get(tile.id);
+            let rect = def.collision.rect(x, y, tile.rotation)?;
+            let one_way = def.one_way.then(|| Vec2::from_angle((tile.rotation % 4) as f32 * FRAC_PI_2).round());
+            Some(Solid { rect, one_way })
+        })
+        .collect()
+}
+
+/// Moves a body at `from` by `delta`, one axis at a time, stopping at solid tiles.
+/// Returns where it ended up and the axes it was stopped on.
+pub fn slide(map: &Map, from: Vec2, delta: Vec2) -> (Vec2, BVec2) {
+    let mut position = from;
+    let mut blocked = BVec2::FALSE;
+
+    // --- Horizontal movement ---
+    let before = body_rect(position);
+    position.x += delta.x;
+    for solid in solids_in(map, body_rect(position)) {
+        if !overlaps(&body_rect(position), &solid.rect) || !solid.stops(&before, vec_x(delta)) {
+            continue;
+        }
+        if delta.x > 0.0 {
+            // Moving right: push back to the left of the tile
+            position.x = solid.rect.x - PLAYER_WIDTH / 2.0;
+            blocked.x = true;
+        } else if delta.x < 0.0 {
+            // Moving left: push to the right of the tile
+            position.x = solid.rect.right() + PLAYER_WIDTH / 2.0;
+            blocked.x = true;
+        }
+    }
+
+    // --- Vertical movement ---
+    let before = body_rect(position);
+    position.y += delta.y;
+    for solid in solids_in(map, body_rect(position)) {
+        if !overlaps(&body_rect(position), &solid.rect) || !solid.stops(&before, vec_y(delta)) {
+            continue;
+        }
+        if delta.y > 0.0 {
+            // Moving down: push back up
+            position.y = solid.rect.y - PLAYER_HEIGHT / 2.0;
+            blocked.y = true;
+        } else if delta.y < 0.0 {
+            // Moving up: push down
+            position.y = solid.rect.bottom() + PLAYER_HEIGHT / 2.0;
+            blocked.y = true;
+        }
+    }
+
+    (position, blocked)
+}
+
+fn vec_x(v: Vec2) -> Vec2 {
+    Vec2::new(v.x, 0.0)
+}
+
+fn vec_y(v: Vec2) -> Vec2 {
+    Vec2::new(0.0, v.y)
+}
+
+/// Fastest a player can walk at `position`, diagonally.
+fn max_speed(map: &Map, position: Vec2) -> f32 {
+    let speed = map.ground_at(position).map_or(1.0, |tile| tile.speed);
+    Vec2::splat(PLAYER_SPEED * speed).length().max(f32::EPSILON)
+}
+
+/// Health lost per second by a player standing at `position`.
+pub fn hazard_damage(map: &Map, position: Vec2) -> u32 {
+    let tile = (position / TILE_SIZE).floor();
+    map.tile_defs(tile.x as i64, tile.y as i64).map(|def| def.damage).max().unwrap_or(0)
+}
+
+/// Checks the positions a client reports on the server, so players can't walk through
+/// walls or move faster than the ground they walk on allows.
+#[derive(Debug, Default)]
+pub struct MoveValidator {
+    position: Option<Vec2>,
+    /// Seconds of movement the player has saved up, refilled over time.
+    budget: f32,
+    /// Whether the player may be sent back to the spawn point, after dying or a map change.
+    respawn_allowed: bool,
+}
+
+impl MoveValidator {
+    /// Forgets the last position, trusting the next one reported.
+    pub fn reset(&mut self) {
+        self.position = None;
+        self.respawn_allowed = true;
+    }
+
+    pub fn died(&mut self) {
+        self.respawn_allowed = true;
+    }
+
+    /// The player was sent back to the spawn point. Ignored unless they died or the map
+    /// changed since the last respawn, so it can't be used to teleport.
+    pub fn respawn(&mut self) {
+        if self.respawn_allowed {
+            self.position = Some(SPAWN_POINT);
+            self.respawn_allowed = false;
+        }
+    }
+
+    /// Replays the move to the `reported` position, `dt` seconds after the previous one,
+    /// and returns where the player may actually be.
+    pub fn check(&mut self, map: &Map, reported: Vec2, dt: f32) -> Vec2 {
+        let Some(from) = self.position else {
+            self.position = Some(reported);
+            return reported;
+        };
+        self.budget = (self.budget + dt).min(MOVE_BUDGET_TIME);
+
+        let delta = reported - from;
+        let steps = (delta.length() / REPLAY_STEP).ceil().max(1.0);
+        let step = delta / steps;
+        let mut position = from;
+        for _ in 0..steps as u32 {
+            let next = slide(map, position, step).0;
+            // Paid for at the speed of the faster ground on either end, as the client only
+            // slows down once it is on the slower one
+            let speed = max_speed(map, position).max(max_speed(map, next));
+            let cost = position.distance(next) / speed;
+            if cost > self.budget {
+                position = slide(map, position, step * (self.budget / cost)).0;
+                self.budget = 0.0;
+                break;
+            }
+            self.budget -= cost;
+            position = next;
+        }
+
+        if position.distance(reported) <= CORRECTION_TOLERANCE {
+            position = reported;
+        }
+        self.position = Some(position);
+        position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Tile, EMPTY_TILE};
+    use crate::tileset::Tileset;
+
+    const ROCK: u16 = 2;
+    const MUD: u16 = 5;
+    const ICE: u16 = 6;
+    const LAVA: u16 = 7;
+    const GATE: u16 = 9;
+
+    /// A 20x20 map of `ground`, with the tile at the center of each position in `walls`.
+    fn map_of(ground: u16, walls: &[(u16, Vec2, u8)]) -> Map {
+        let tileset = Tileset::from_json(include_str!("../res/tileset.json")).unwrap();
+        let mut map = Map::new(20, 20, tileset);
+        let grid = map.layer_mut(LayerKind::Ground);
+        for y in 0..20 {
+            for x in 0..20 {
+                grid.get_mut(x, y).unwrap().id = ground;
+            }
+        }
+        for &(id, at, rotation) in walls {
+            let (x, y) = ((at.x / TILE_SIZE) as usize, (at.y / TILE_SIZE) as usize);
+            *map.layer_mut(LayerKind::Walls).get_mut(x, y).unwrap() = Tile { id, variant: 0, rotation };
+        }
+        map
+    }
+
+    /// Steps a body for `seconds` at 60 steps per second.
+    fn walk(map: &Map, body: &mut Body, input: Vec2, seconds: f32) {
+        for _ in 0..(seconds * 60.0).round() as u32 {
+            body.step(map, input, 1.0 / 60.0);
+        }
+    }
+
+    fn at(x: f32, y: f32) -> Vec2 {
+        Vec2::new(x, y) * TILE_SIZE
+    }
+
+    #[test]
+    fn normal_ground_moves_at_full_speed_and_stops_at_once() {
+        let map = map_of(EMPTY_TILE.id, &[]);
+        let mut body = Body { position: at(2.0, 10.0), ..Default::default() };
+        walk(&map, &mut body, Vec2::X, 1.0);
+        assert!((body.position.x - (at(2.0, 0.0).x + PLAYER_SPEED)).abs() < 0.1);
+        walk(&map, &mut body, Vec2::ZERO, 1.0 / 60.0);
+        assert_eq!(body.velocity, Vec2::ZERO);
+    }
+
+    #[test]
+    fn mud_slows_players_down() {
+        let map = map_of(MUD, &[]);
+        let mut body = Body { position: at(2.0, 10.0), ..Default::default() };
+        walk(&map, &mut body, Vec2::X, 1.0);
+        assert!((body.position.x - (at(2.0, 0.0).x + PLAYER_SPEED * 0.5)).abs() < 0.1);
+    }
+
+    #[test]
+    fn ice_keeps_momentum() {
+        let map = map_of(ICE, &[]);
+        let mut body = Body { position: at(2.0, 10.0), ..Default::default() };
+        walk(&map, &mut body, Vec2::X, 0.1);
+        // Barely got going
+        assert!(body.velocity.x < PLAYER_SPEED / 2.0);
+        walk(&map, &mut body, Vec2::X, 1.0);
+        let released_at = body.position.x;
+        walk(&map, &mut body, Vec2::ZERO, 0.1);
+        // Still sliding after letting go
+        assert!(body.velocity.x > PLAYER_SPEED / 2.0);
+        assert!(body.position.x > released_at + 10.0);
+    }
+
+    #[test]
+    fn walls_stop_players() {
+        let map = map_of(EMPTY_TILE.id, &[(ROCK, at(5.5, 10.5), 0)]);
+        let mut body = Body { position: at(2.5, 10.5), ..Default::default() };
+        walk(&map, &mut body, Vec2::X, 1.0);
+        assert_eq!(body.position.x, at(5.0, 0.0).x - PLAYER_WIDTH / 2.0);
+        assert_eq!(body.velocity.x, 0.0);
+    }
+
+    #[test]
+    fn players_slide_along_walls() {
+        let map = map_of(EMPTY_TILE.id, &[(ROCK, at(5.5, 10.5), 0)]);
+        let start = at(5.0, 10.5) - Vec2::new(PLAYER_WIDTH / 2.0, 0.0);
+        let mut body = Body { position: start, ..Default::default() };
+        walk(&map, &mut body, Vec2::new(1.0, 1.0), 0.1);
+        assert_eq!(body.position.x, start.x);
+        assert!(body.position.y > start.y + 20.0);
+    }
+
+    #[test]
+    fn one_way_tiles_only_let_players_through_their_way() {
+        // Rotated a quarter turn clockwise, so it lets players through going down
+        let map = map_of(EMPTY_TILE.id, &[(GATE, at(5.5, 10.5), 1)]);
+
+        let mut body = Body { position: at(5.5, 8.0), ..Default::default() };
+        walk(&map, &mut body, Vec2::Y, 1.0);
+        assert!(body.position.y > at(0.0, 13.0).y);
+
+        let mut body = Body { position: at(5.5, 13.0), ..Default::default() };
+        walk(&map, &mut body, -Vec2::Y, 1.0);
+        assert_eq!(body.position.y, at(0.0, 11.0).y + PLAYER_HEIGHT / 2.0);
+
+        let mut body = Body { position: at(2.5, 10.5), ..Default::default() };
+        walk(&map, &mut body, Vec2::X, 1.0);
+        assert_eq!(body.position.x, at(5.0, 0.0).x - PLAYER_WIDTH / 2.0);
+    }
+
+    #[test]
+    fn hazards_deal_damage() {
+        let map = map_of(LAVA, &[]);
+        assert_eq!(hazard_damage(&map, at(3.5, 3.5)), 20);
+        assert_eq!(hazard_damage(&map, at(-3.0, 3.5)), 0);
+        assert_eq!(hazard_damage(&map_of(EMPTY_TILE.id, &[]), at(3.5, 3.5)), 0);
+    }
+
+    #[test]
+    fn validator_accepts_walking() {
+        let map = map_of(MUD, &[]);
+        let mut validator = MoveValidator::default();
+        let mut body = Body { position: at(2.5, 10.5), ..Default::default() };
+        assert_eq!(validator.check(&map, body.position, 0.0), body.position);
+        for _ in 0..60 {
+            body.step(&map, Vec2::new(1.0, 1.0), 1.0 / 60.0);
+            assert_eq!(validator.check(&map, body.position, 1.0 / 60.0), body.position);
+        }
+    }
+
+    #[test]
+    fn validator_stops_players_going_through_walls() {
+        let map = map_of(EMPTY_TILE.id, &[(ROCK, at(5.5, 10.5), 0)]);
+        let mut validator = MoveValidator::default();
+        validator.check(&map, at(4.0, 10.5), 0.0);
+        let position = validator.check(&map, at(7.0, 10.5), 0.1);
+        assert_eq!(position.x, at(5.0, 0.0).x - PLAYER_WIDTH / 2.0);
+        // Later moves start from the corrected position
+        assert_eq!(validator.check(&map, position - Vec2::X, 0.1), position - Vec2::X);
+    }
+
+    #[test]
+    fn validator_limits_speed() {
+        let map = map_of(EMPTY_TILE.id, &[]);
+        let mut validator = MoveValidator::default();
+        validator.check(&map, at(2.0, 2.0), 0.0);
+        let position = validator.check(&map, at(18.0, 2.0), 0.1);
+        assert!(position.x < at(2.0, 0.0).x + PLAYER_SPEED);
+
+        validator.died();
+        validator.respawn();
+        assert_eq!(validator.check(&map, SPAWN_POINT + Vec2::X, 0.1), SPAWN_POINT + Vec2::X);
+    }
+
+    #[test]
+    fn validator_limits_speed_to_the_ground() {
+        let map = map_of(MUD, &[]);
+        let mut validator = MoveValidator::default();
+        let start = at(2.0, 10.0);
+        validator.check(&map, start, 0.0);
+        // Walking as fast as on normal ground
+        let position = validator.check(&map, start + Vec2::X * PLAYER_SPEED * 0.1, 0.1);
+        let mud_speed = Vec2::splat(PLAYER_SPEED * 0.5).length();
+        assert!((position.x - (start.x + mud_speed * 0.1)).abs() < 0.1);
+    }
+
+    #[test]
+    fn validator_only_respawns_players_that_died() {
+        let map = map_of(EMPTY_TILE.id, &[]);
+        let mut validator = MoveValidator::default();
+        let far = at(18.0, 18.0);
+        validator.check(&map, far, 0.0);
+        validator.respawn();
+        assert_eq!(validator.check(&map, SPAWN_POINT, 0.0), far);
+
+        validator.died();
+        validator.respawn();
+        assert_eq!(validator.check(&map, SPAWN_POINT, 0.0), SPAWN_POINT);
+        // Once per death
+        let near = SPAWN_POINT + Vec2::X * 200.0;
+        assert_eq!(validator.check(&map, near, 1.0), near);
+        validator.respawn();
+        // Walking back is still limited by the speed budget
+        assert!(validator.check(&map, SPAWN_POINT, 0.0).distance(SPAWN_POINT) > 150.0);
+    }
+}
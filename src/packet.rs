@@ -1,5 +1,8 @@
+use crate::gamemode::{FlagInfo, MatchInfo, MatchResult, ScoreboardEntry, Team};
+use crate::ghost::Ghost;
 use crate::item::{ItemKind, WeaponKind};
-use crate::map::Map;
+use crate::leaderboard::LeaderboardEntry;
+use crate::map::{ItemChange, Map, TileChange};
 use crate::player::{ActionType, Player};
 use bincode::{self, Decode, Encode};
 use std::io::{Error, Read, Write};
@@ -17,6 +20,43 @@ impl MapPacket {
     }
 }
 
+/// Everything sent over the game connection after the map handshake.
+#[derive(Clone, Debug, Decode, Encode)]
+pub enum Packet {
+    /// Player state, sent by clients and relayed by the server to everyone else.
+    Player(PlayerPacket),
+    /// Client asks for the top N lap times on the current map.
+    LeaderboardRequest(u32),
+    Leaderboard(Vec<LeaderboardEntry>),
+    /// Announcement from the server, shown in the chat feed.
+    ServerMessage(String),
+    /// Client shares its lap so the server can keep it if it is the map record.
+    GhostUpload(Ghost),
+    /// Client asks for the record holder's ghost on the current map.
+    GhostRequest,
+    Ghost(Option<Ghost>),
+    /// Rules of the current match and the receiving player's team.
+    MatchInfo(MatchInfo),
+    MatchEnd(MatchResult),
+    /// Where every capture the flag flag is, sent whenever one moves between base, carrier and ground.
+    Flags(Vec<FlagInfo>),
+    /// Sent by the server with its clock in milliseconds, echoed back as `Pong` to measure ping.
+    Ping(u64),
+    Pong(u64),
+    /// Scores and pings of every player in the match, sent periodically.
+    Scoreboard(Vec<ScoreboardEntry>),
+    /// The server moved on to another map, which replaces the current one.
+    ChangeMap(Map),
+    /// The server didn't accept the last reported position and put the player back here.
+    SetPosition(f32, f32),
+    /// Damage dealt to the receiving player by the hazard tile it stands on.
+    Hurt(u32),
+    /// Tiles destroyed on the server, to apply to the local copy of the map.
+    TileChanged(Vec<TileChange>),
+    /// Items picked up or dropped before the receiving player joined.
+    ItemsChanged(Vec<ItemChange>),
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Decode, Encode)]
 pub struct PlayerPacket {
     pub name: String,
@@ -25,8 +65,10 @@ pub struct PlayerPacket {
     pub y: f32,
     pub message: String,
     pub dir: bool,
+    pub aim: f32,
     pub actions: Vec<ActionType>,
     pub current_weapon_kind: Option<WeaponKind>,
+    pub team: Option<Team>,
 }
 
 impl PlayerPacket {
@@ -38,19 +80,20 @@ impl PlayerPacket {
             y: player.y,
             message: player.message.clone(),
             dir: player.dir,
+            aim: player.aim,
             actions: player.actions.clone(),
-            current_weapon_kind: player.items
-                .get(player.current_item)
-                .and_then(|item| match &item.kind {
-                    ItemKind::Weapon(weapon) => Some(weapon.weapon_kind.clone()),
-                    _ => None,
+            current_weapon_kind: player
+                .current_item()
+                .map(|item| match &item.kind {
+                    ItemKind::Weapon(weapon) => weapon.weapon_kind.clone(),
                 }),
+            team: player.team,
         }
     }
 }
 
-/// Sends a PlayerPacket with a 4-byte length prefix, then the bincode-encoded data.
-pub fn send_packet(stream: &mut TcpStream, packet: &PlayerPacket) -> Result<(), Error> {
+/// Sends a Packet with a 4-byte length prefix, then the bincode-encoded data.
+pub fn send_packet(stream: &mut TcpStream, packet: &Packet) -> Result<(), Error> {
     let encoded = bincode::encode_to_vec(packet, bincode::config::standard()).unwrap();
     let len_bytes = (encoded.len() as u32).to_be_bytes();
 
@@ -59,8 +102,8 @@ pub fn send_packet(stream: &mut TcpStream, packet: &PlayerPacket) -> Result<(),
     Ok(())
 }
 
-/// Receives a PlayerPacket by first reading 4 bytes length prefix, then that many bytes of data.
-pub fn receive_packet(stream: &mut TcpStream) -> Result<PlayerPacket, Error> {
+/// Receives a Packet by first reading 4 bytes length prefix, then that many bytes of data.
+pub fn receive_packet(stream: &mut TcpStream) -> Result<Packet, Error> {
     let mut size_buf = [0u8; 4];
     stream.read_exact(&mut size_buf)?;
     let size = u32::from_be_bytes(size_buf) as usize;
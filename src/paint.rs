@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+
+use macroquad::math::Vec2;
+
+use crate::common::TILE_SIZE;
+use crate::tilegrid::TileGrid;
+
+/// Tile position under a world position. Can lie outside the map.
+pub fn tile_at(position: Vec2) -> (i64, i64) {
+    let tile = (position / TILE_SIZE).floor();
+    (tile.x as i64, tile.y as i64)
+}
+
+/// Tiles covered by a square brush `size` tiles wide centered on `center`. Even sizes
+/// reach further right and down.
+pub fn brush(center: (i64, i64), size: u32) -> impl Iterator<Item = (i64, i64)> {
+    let size = size.max(1) as i64;
+    let first = (center.0 - (size - 1) / 2, center.1 - (size - 1) / 2);
+    (0..size).flat_map(move |dy| (0..size).map(move |dx| (first.0 + dx, first.1 + dy)))
+}
+
+/// Tiles along the line between two tiles, both included, each touching the previous one.
+pub fn line(from: (i64, i64), to: (i64, i64)) -> Vec<(i64, i64)> {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (step_x, step_y) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+    let mut error = dx + dy;
+    let mut tile = from;
+    let mut tiles = vec![tile];
+    while tile != to {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            tile.0 += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            tile.1 += step_y;
+        }
+        tiles.push(tile);
+    }
+    tiles
+}
+
+/// Tiles of the rectangle with opposite corners `a` and `b`, only its border unless `filled`.
+pub fn rect(a: (i64, i64), b: (i64, i64), filled: bool) -> Vec<(i64, i64)> {
+    let (left, right) = (a.0.min(b.0), a.0.max(b.0));
+    let (top, bottom) = (a.1.min(b.1), a.1.max(b.1));
+    (top..=bottom)
+        .flat_map(|y| (left..=right).map(move |x| (x, y)))
+        .filter(|&(x, y)| filled || x == left || x == right || y == top || y == bottom)
+        .collect()
+}
+
+/// Tiles reachable from `start` through tiles sharing an edge with the same id as it,
+/// `start` included.
+pub fn flood_fill(grid: &TileGrid, start: (usize, usize)) -> Vec<(usize, usize)> {
+    let Some(id) = grid.get(start.0, start.1).map(|tile| tile.id) else {
+        return Vec::new();
+    };
+    let width = grid.width() as usize;
+    let mut visited = vec![false; width * grid.height() as usize];
+    let mut queue = VecDeque::from([start]);
+    visited[start.1 * width + start.0] = true;
+    let mut tiles = Vec::new();
+    while let Some((x, y)) = queue.pop_front() {
+        tiles.push((x, y));
+        let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+        for (nx, ny) in neighbours {
+            if grid.get(nx, ny).is_some_and(|tile| tile.id == id) && !visited[ny * width + nx] {
+                visited[ny * width + nx] = true;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Tile, EMPTY_TILE};
+
+    #[test]
+    fn brush_is_centered() {
+        let tiles: Vec<_> = brush((5, 5), 3).collect();
+        assert_eq!(tiles.len(), 9);
+        assert_eq!((tiles[0], tiles[8]), ((4, 4), (6, 6)));
+        assert_eq!(brush((5, 5), 1).collect::<Vec<_>>(), [(5, 5)]);
+        assert_eq!(brush((5, 5), 2).last(), Some((6, 6)));
+    }
+
+    #[test]
+    fn lines_have_no_gaps() {
+        assert_eq!(line((0, 0), (3, 0)), [(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(line((2, 2), (2, 2)), [(2, 2)]);
+        let diagonal = line((7, 1), (0, 5));
+        assert_eq!((diagonal[0], *diagonal.last().unwrap()), ((7, 1), (0, 5)));
+        for pair in diagonal.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert!((a.0 - b.0).abs() <= 1 && (a.1 - b.1).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn rects_from_any_corner() {
+        assert_eq!(rect((2, 2), (0, 0), true).len(), 9);
+        let outline = rect((0, 2), (2, 0), false);
+        assert_eq!(outline.len(), 8);
+        assert!(!outline.contains(&(1, 1)));
+    }
+
+    #[test]
+    fn flood_fill_stays_within_the_region() {
+        let rock = Tile { id: 2, variant: 0, rotation: 0 };
+        // A wall of rock splitting the grid in two, and a rotated tile that still counts as
+        // the same kind as its neighbours
+        let mut grid = TileGrid::new(5, 3, EMPTY_TILE);
+        for y in 0..3 {
+            *grid.get_mut(2, y).unwrap() = rock;
+        }
+        grid.get_mut(0, 0).unwrap().rotation = 1;
+
+        let mut left = flood_fill(&grid, (0, 2));
+        left.sort();
+        assert_eq!(left, [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2)]);
+        assert_eq!(flood_fill(&grid, (2, 1)).len(), 3);
+        assert!(flood_fill(&grid, (9, 9)).is_empty());
+    }
+}
@@ -1,13 +1,24 @@
-use std::u64;
-
 use bincode::{Decode, Encode};
 use macroquad::rand::gen_range;
 
-use crate::item::{Item, WeaponKind};
+use crate::gamemode::Team;
+use crate::item::{Item, ItemKind, Weapon};
+use crate::shot::Shot;
+
+/// Number of inventory slots, one per number key (1-0).
+pub const INVENTORY_SIZE: usize = 10;
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Decode, Encode)]
 pub enum ActionType {
-    Shot((WeaponKind, f32, f32, f32, f32)),
-    PickUp(u64)
+    Shot(Shot),
+    PickUp(u64),
+    Drop(Item),
+    /// A race lap was completed, with the lap time in seconds.
+    LapFinished(f32),
+    /// The player was killed by the player with this id (their own id for suicides).
+    Died(u64),
+    /// The player was sent back to the spawn point.
+    Respawned,
 }
 
 
@@ -21,10 +32,13 @@ pub struct Player {
     pub vx: f32,
     pub vy: f32,
     pub dir: bool,
+    /// Direction the player is aiming in, in radians.
+    pub aim: f32,
     pub message: String,
     pub current_item: usize,
-    pub items: Vec<Item>,
-    pub actions: Vec<ActionType>
+    pub items: Vec<Option<Item>>,
+    pub actions: Vec<ActionType>,
+    pub team: Option<Team>,
 }
 
 impl Player {
@@ -38,29 +52,94 @@ impl Player {
             vx: 0.0,
             vy: 0.0,
             dir: false,
+            aim: 0.0,
             message: String::new(),
             current_item: 0,
-            items: Vec::new(),
-            actions: Vec::new()
+            items: vec![None; INVENTORY_SIZE],
+            actions: Vec::new(),
+            team: None,
+        }
+    }
+
+    pub fn current_item(&self) -> Option<&Item> {
+        self.items.get(self.current_item).and_then(|slot| slot.as_ref())
+    }
+
+    /// Puts `item` in the inventory. A weapon of a kind already owned is merged into the
+    /// owned one as reserve ammo instead of taking a new slot.
+    /// Returns false if the item didn't fit.
+    pub fn pick_up(&mut self, mut item: Item) -> bool {
+        let ItemKind::Weapon(ref picked) = item.kind;
+        for owned in self.items.iter_mut().flatten() {
+            let ItemKind::Weapon(ref mut weapon) = owned.kind;
+            if weapon.weapon_kind == picked.weapon_kind {
+                weapon.ammo += picked.magazine + picked.ammo;
+                return true;
+            }
+        }
+
+        match self.items.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                item.picked = true;
+                *slot = Some(item);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the current item out of the inventory, placed at the player's feet.
+    pub fn drop_current(&mut self) -> Option<Item> {
+        let (x, y) = (self.x, self.y);
+        self.items.get_mut(self.current_item)?.take().map(|mut item| {
+            item.x = x;
+            item.y = y;
+            item.picked = false;
+            item
+        })
+    }
+
+    /// Moves the selection `step` occupied slots forward (or backward if negative),
+    /// wrapping around and skipping empty slots.
+    pub fn cycle_item(&mut self, step: i32) {
+        let len = self.items.len() as i32;
+        let mut index = self.current_item as i32;
+        for _ in 0..step.abs() {
+            for _ in 0..len {
+                index = (index + step.signum()).rem_euclid(len);
+                if self.items[index as usize].is_some() {
+                    break;
+                }
+            }
+        }
+        if self.items[index as usize].is_some() {
+            self.current_item = index as usize;
         }
     }
 }
 
 impl Player {
     pub fn from_player_packet(packet: &crate::packet::PlayerPacket) -> Self {
-        Self {
+        let mut player = Self {
             health: 100,
-            id: gen_range(u64::MIN, u64::MAX),
+            id: packet.id,
             name: packet.name.to_string(),
             x: packet.x,
             y: packet.y,
             vx: 0.0,
             vy: 0.0,
             dir: packet.dir,
+            aim: packet.aim,
             message: packet.message.clone(),
             current_item: 0,
-            items: Vec::new(),
+            items: vec![None; INVENTORY_SIZE],
             actions: Vec::new(),
-        }
+            team: packet.team,
+        };
+        player.items[0] = packet
+            .current_weapon_kind
+            .as_ref()
+            .map(|kind| Weapon::item_of_kind(kind, packet.x, packet.y, true));
+        player
     }
 }
@@ -0,0 +1,41 @@
+use macroquad::math::Vec2;
+
+use crate::map::{Map, ObjectKind};
+
+/// Times a player's lap between the map's start and finish objects.
+#[derive(Default)]
+pub struct RaceTimer {
+    elapsed: Option<f32>,
+}
+
+impl RaceTimer {
+    /// Current lap time, if a lap is in progress.
+    pub fn elapsed(&self) -> Option<f32> {
+        self.elapsed
+    }
+
+    /// Advances the timer for a move from `from` to `to` that took `dt` seconds.
+    /// Crossing the start (re)starts the lap; crossing the finish during a lap ends it
+    /// and returns the lap time.
+    pub fn update(&mut self, map: &Map, from: Vec2, to: Vec2, dt: f32) -> Option<f32> {
+        if let Some(elapsed) = self.elapsed.as_mut() {
+            *elapsed += dt;
+        }
+
+        for object in &map.objects {
+            if !object.shape.crossed(from, to) {
+                continue;
+            }
+            match object.kind {
+                ObjectKind::StartLine => self.elapsed = Some(0.0),
+                ObjectKind::FinishLine => {
+                    if let Some(lap) = self.elapsed.take() {
+                        return Some(lap);
+                    }
+                }
+                ObjectKind::FlagBase(_) => {}
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,62 @@
+use macroquad::prelude::*;
+
+use crate::gamemode::Team;
+use crate::common::TILE_SIZE;
+use crate::map::{LayerKind, Map, MapObject, ObjectKind, ObjectShape};
+use crate::resources::Resources;
+
+pub fn team_color(team: Option<Team>) -> Color {
+    match team {
+        Some(Team::Red) => RED,
+        Some(Team::Blue) => BLUE,
+        None => BLACK,
+    }
+}
+
+pub fn object_color(kind: &ObjectKind) -> Color {
+    match kind {
+        ObjectKind::StartLine => GREEN,
+        ObjectKind::FinishLine => RED,
+        ObjectKind::FlagBase(team) => team_color(Some(*team)),
+    }
+}
+
+/// Draws a flag on a pole standing at (x, y).
+pub fn draw_flag(x: f32, y: f32, team: Team) {
+    draw_line(x, y, x, y - 36.0, 3.0, DARKGRAY);
+    draw_triangle(vec2(x, y - 36.0), vec2(x + 22.0, y - 29.0), vec2(x, y - 22.0), team_color(Some(team)));
+}
+
+pub fn draw_map_object(object: &MapObject) {
+    let color = object_color(&object.kind);
+    match object.shape {
+        ObjectShape::Line { x1, y1, x2, y2 } => draw_line(x1, y1, x2, y2, 4.0, color),
+        ObjectShape::Rect { x, y, w, h } => {
+            draw_rectangle(x, y, w, h, Color::new(color.r, color.g, color.b, 0.25));
+            draw_rectangle_lines(x, y, w, h, 2.0, color);
+        }
+    }
+}
+
+/// Draws the tiles of a layer visible through `camera`, tinted by `tint`.
+pub fn draw_layer(map: &Map, kind: LayerKind, camera: &Camera2D, resources: &Resources, tint: Color) {
+    let top_left = camera.screen_to_world(vec2(0.0, 0.0));
+    let bottom_right = camera.screen_to_world(vec2(screen_width(), screen_height()));
+    let view = Rect::new(top_left.x, top_left.y, bottom_right.x - top_left.x, bottom_right.y - top_left.y);
+    for (x, y, tile) in map.tiles_in(kind, view) {
+        let Some(texture) = resources.tile_texture(&map.tileset, tile) else {
+            continue;
+        };
+        draw_texture_ex(
+            texture,
+            x as f32 * TILE_SIZE,
+            y as f32 * TILE_SIZE,
+            tint,
+            DrawTextureParams {
+                dest_size: Some(vec2(TILE_SIZE, TILE_SIZE)),
+                rotation: (tile.rotation % 4) as f32 * std::f32::consts::FRAC_PI_2,
+                ..Default::default()
+            },
+        );
+    }
+}
@@ -1,6 +1,12 @@
 use macroquad::prelude::*;
 use macroquad::audio::{load_sound, Sound};
 
+use crate::item::WeaponKind;
+use std::collections::HashMap;
+
+use crate::map::Tile;
+use crate::tileset::Tileset;
+
 pub struct Resources {
     pub player_texture: Texture2D,
 
@@ -9,6 +15,10 @@ pub struct Resources {
     pub weapon_ak47_shot_sound: Sound,
 
     pub weapon_magnum_texture_picked: Texture2D,
+    pub weapon_magnum_texture: Texture2D,
+
+    /// Textures used by the local tileset, by file name.
+    pub tile_textures: HashMap<String, Texture2D>,
 
     pub chat_sound: Sound,
 }
@@ -21,7 +31,17 @@ impl Resources {
         let weapon_ak47_texture = load_texture("res/weapon_ak47.png").await.unwrap();
         let weapon_ak47_shot_sound = load_sound("res/weapon_ak47_shot.wav").await.unwrap();
 
-        let weapon_magnum_texture_picked = load_texture("res/weapon_magnum.png").await.unwrap();
+        let weapon_magnum_texture = load_texture("res/weapon_magnum.png").await.unwrap();
+        // The magnum has no separate picked-up art, share the texture
+        let weapon_magnum_texture_picked = weapon_magnum_texture.clone();
+
+        let tileset = Tileset::load().unwrap();
+        let mut tile_textures = HashMap::new();
+        for path in tileset.tiles.iter().flat_map(|tile| &tile.textures) {
+            if !tile_textures.contains_key(path) {
+                tile_textures.insert(path.clone(), load_texture(path).await.unwrap());
+            }
+        }
 
         let chat_sound = load_sound("res/chat.wav").await.unwrap();
 
@@ -31,7 +51,27 @@ impl Resources {
             weapon_ak47_texture,
             weapon_ak47_shot_sound,
             weapon_magnum_texture_picked,
+            weapon_magnum_texture,
+            tile_textures,
             chat_sound,
         }
     }
+
+    /// Texture a tile is drawn with, none for tiles without textures or whose texture
+    /// isn't part of the local tileset.
+    pub fn tile_texture(&self, tileset: &Tileset, tile: &Tile) -> Option<&Texture2D> {
+        let textures = &tileset.get(tile.id).textures;
+        if textures.is_empty() {
+            return None;
+        }
+        self.tile_textures.get(&textures[tile.variant as usize % textures.len()])
+    }
+
+    /// Texture for a weapon lying on the ground or shown in the inventory.
+    pub fn weapon_texture(&self, weapon_kind: &WeaponKind) -> &Texture2D {
+        match weapon_kind {
+            WeaponKind::Ak47 => &self.weapon_ak47_texture,
+            WeaponKind::Magnum => &self.weapon_magnum_texture,
+        }
+    }
 }
@@ -1,12 +1,137 @@
 use crate::debugutils::log;
-use crate::map::Map;
-use crate::packet::{self, MapPacket};
+use crate::gamemode::{Match, MatchPhase, MatchSettings};
+use crate::ghost::{Ghost, RECORD_GHOSTS};
+use crate::leaderboard::Leaderboards;
+use crate::map::{ItemChange, Map, TileChange};
+use crate::movement::{self, MoveValidator};
+use crate::race::RaceTimer;
+use crate::shot::ShotValidator;
+use crate::tileset::Tileset;
+use crate::packet::{self, MapPacket, Packet, PlayerPacket};
+use crate::player::ActionType;
+use macroquad::math::{vec2, Vec2};
+use serde::{Deserialize, Serialize};
 use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{process, fs, thread, env};
-use sha2::{self, Digest, Sha256, Sha256VarCore};
-type ClientList = Arc<Mutex<Vec<TcpStream>>>;
+use sha2::{self, Digest, Sha256};
+
+const CONFIG_FILE: &str = "server.json";
+/// Match clock ticks between two pings and scoreboard updates.
+const SCOREBOARD_TICKS: u32 = 2;
+/// Match clock ticks between two rounds of hazard damage, which is dealt per second.
+const HAZARD_TICKS: u32 = 2;
+/// Seconds an uploaded ghost's lap time may differ from the one timed on the server, which
+/// counts from the arrival of the packets rather than the client's frames.
+const GHOST_TIME_TOLERANCE: f32 = 0.5;
+/// Longest a kill may be reported after the killer's last accepted shot, which has to reach
+/// the victim's client first.
+const KILL_SHOT_WINDOW: Duration = Duration::from_secs(2);
+
+/// Server options, read from `server.json` next to the executable's working directory.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+struct ServerConfig {
+    #[serde(rename = "match")]
+    match_settings: MatchSettings,
+    /// Map files played in turn, one per match.
+    maps: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            match_settings: MatchSettings::default(),
+            maps: vec!["map.json".to_string()],
+        }
+    }
+}
+
+impl ServerConfig {
+    fn load() -> Self {
+        match fs::read_to_string(CONFIG_FILE) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}, using defaults", CONFIG_FILE, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    /// Id of the player on this connection, known after their first packet.
+    player_id: Option<u64>,
+    /// Last measured round trip time in milliseconds.
+    ping: Option<u32>,
+    /// Last accepted position of the player.
+    position: Option<Vec2>,
+    /// When the player last fired a shot the server accepted.
+    last_shot: Option<Instant>,
+}
+
+/// The map being played. Players get it as loaded followed by the tiles and items changed
+/// since, so it hashes the same for everyone.
+struct CurrentMap {
+    /// The map with every change applied, used for the server's own checks.
+    map: Map,
+    /// The map as loaded, along with its encoded form sent in the handshake.
+    original: Map,
+    data: Vec<u8>,
+    hash: String,
+    /// Tiles changed since the map was loaded, in order.
+    changes: Vec<TileChange>,
+    /// Items picked up and dropped since the map was loaded, in order.
+    item_changes: Vec<ItemChange>,
+    /// Bumped every time the rotation moves on, even to the same map again, so players
+    /// are expected back at the spawn point.
+    generation: u64,
+}
+
+impl CurrentMap {
+    /// Loads a map file along with the current tileset, so edits to either show up the next
+    /// time the map comes around in the rotation.
+    fn load(path: &str) -> Option<Self> {
+        let tileset = match Tileset::load() {
+            Ok(tileset) => tileset,
+            Err(e) => {
+                eprintln!("Failed to load tileset: {}", e);
+                return None;
+            }
+        };
+        let map = match fs::read_to_string(path).map(|data| Map::from_json(&data, tileset)) {
+            Ok(Ok(map)) => map,
+            Ok(Err(e)) => {
+                eprintln!("Failed to parse map {}: {}", path, e);
+                return None;
+            }
+            Err(e) => {
+                eprintln!("Failed to read map {}: {}", path, e);
+                return None;
+            }
+        };
+        println!("Loaded map {} ({}x{} tiles)", path, map.width(), map.height());
+        let hash = map.hash();
+        let data = MapPacket { data: map.clone() }.serialize();
+        Some(Self { original: map.clone(), map, data, hash, changes: Vec::new(), item_changes: Vec::new(), generation: 0 })
+    }
+}
+
+/// State shared by every connection thread. Locks are taken in the order
+/// `clients`, `game`, `map` when several are needed.
+struct ServerState {
+    clients: Mutex<Vec<Client>>,
+    leaderboards: Mutex<Leaderboards>,
+    game: Mutex<Match>,
+    map: Mutex<CurrentMap>,
+    /// Map files played in turn.
+    rotation: Vec<String>,
+    /// Reference for the timestamps sent in pings.
+    started: Instant,
+}
 
 pub fn main() {
     let listener = match TcpListener::bind("127.0.0.1:8080") {
@@ -16,15 +141,21 @@ pub fn main() {
             process::exit(1);
         }
     };
-    let clients: ClientList = Arc::new(Mutex::new(Vec::new()));
-    let map: Map = serde_json::from_str(
-        String::from_utf8(fs::read("map.json").unwrap())
-            .unwrap()
-            .as_str(),
-    )
-    .unwrap();
+    let config = ServerConfig::load();
+    let Some(first_map) = config.maps.first().and_then(|path| CurrentMap::load(path)) else {
+        eprintln!("Could not load the first map of the rotation");
+        process::exit(1);
+    };
 
-    let serialized_map = MapPacket { data: map }.serialize();
+    println!("Game mode: {:?}", config.match_settings.mode);
+    let state = Arc::new(ServerState {
+        clients: Mutex::new(Vec::new()),
+        leaderboards: Mutex::new(Leaderboards::load()),
+        game: Mutex::new(Match::new(config.match_settings, &first_map.map)),
+        map: Mutex::new(first_map),
+        rotation: config.maps,
+        started: Instant::now(),
+    });
 
     let exe = env::current_exe().unwrap();
     let mut sha256 = Sha256::new();
@@ -32,17 +163,19 @@ pub fn main() {
     let hash = sha256.finalize();
     println!("Server started! ~ Hash: {:x}", hash);
 
+    let ticker_state = Arc::clone(&state);
+    thread::spawn(move || run_match_clock(ticker_state));
+
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 let stream_clone = stream.try_clone().unwrap();
-                let clients = Arc::clone(&clients);
-                let map_data = serialized_map.clone();
+                let state = Arc::clone(&state);
 
-                clients.lock().unwrap().push(stream_clone);
+                state.clients.lock().unwrap().push(Client { stream: stream_clone, player_id: None, ping: None, position: None, last_shot: None });
 
                 thread::spawn(move || {
-                    handle_client(stream, clients, map_data);
+                    handle_client(stream, state);
                 });
             }
             Err(e) => {
@@ -52,8 +185,88 @@ pub fn main() {
     }
 }
 
-pub fn handle_client(mut stream: TcpStream, clients: ClientList, map: Vec<u8>) {
-    let map_packet_size = map.len();
+/// Drives the match through warmup, countdown, live and post-game, moving on to the next
+/// map of the rotation after each match. Also pings every client and keeps their
+/// scoreboards up to date.
+fn run_match_clock(state: Arc<ServerState>) {
+    let mut rotation_index = 0;
+    let mut ticks = 0;
+    loop {
+        thread::sleep(Duration::from_millis(500));
+        ticks += 1;
+
+        let mut clients = state.clients.lock().unwrap();
+        let mut game = state.game.lock().unwrap();
+
+        if ticks % SCOREBOARD_TICKS == 0 {
+            let now = state.started.elapsed().as_millis() as u64;
+            broadcast(&mut clients, &Packet::Ping(now), None);
+            let scoreboard = game.scoreboard(|id| {
+                clients.iter().find(|client| client.player_id == Some(id)).and_then(|client| client.ping)
+            });
+            broadcast(&mut clients, &Packet::Scoreboard(scoreboard), None);
+        }
+
+        if ticks % HAZARD_TICKS == 0 {
+            hurt_players(&state, &mut clients);
+        }
+
+        let events = game.tick();
+        announce(&mut clients, &game, events);
+        if let Some(result) = game.check_end() {
+            broadcast(&mut clients, &Packet::MatchEnd(result), None);
+        }
+        if game.post_game_over() {
+            rotation_index = next_map(&state, &mut clients, &mut game, rotation_index);
+        }
+        if let Some(phase) = game.update() {
+            let message = match phase {
+                MatchPhase::Warmup => "Not enough players, back to warmup".to_string(),
+                MatchPhase::Countdown => format!("Match starts in {} seconds", game.settings.countdown),
+                MatchPhase::Live => "The match has started!".to_string(),
+                MatchPhase::PostGame => unreachable!("matches only end in check_end"),
+            };
+            println!("{}", message);
+            send_match_info(&mut clients, &game);
+            broadcast(&mut clients, &Packet::Flags(game.flags()), None);
+            broadcast(&mut clients, &Packet::ServerMessage(message), None);
+        }
+    }
+}
+
+/// Loads the map after `index` in the rotation, skipping maps that fail to load, and sends
+/// it to everyone. Returns the index of the map now played.
+fn next_map(state: &ServerState, clients: &mut Vec<Client>, game: &mut Match, index: usize) -> usize {
+    let mut map = state.map.lock().unwrap();
+    for step in 1..=state.rotation.len() {
+        let next = (index + step) % state.rotation.len();
+        let path = &state.rotation[next];
+        // Reloaded even when it's the same file, so edits show up between matches
+        let Some(loaded) = CurrentMap::load(path) else {
+            continue;
+        };
+        println!("Changing map to {} ({})", path, loaded.map.header.name);
+        *map = CurrentMap { generation: map.generation + 1, ..loaded };
+        game.change_map(&map.map);
+        broadcast(clients, &Packet::ChangeMap(map.original.clone()), None);
+        send_match_info(clients, game);
+        broadcast(clients, &Packet::Flags(game.flags()), None);
+        return next;
+    }
+    eprintln!("No map of the rotation could be loaded, staying on the current one");
+    map.generation += 1;
+    game.change_map(&map.map);
+    send_match_info(clients, game);
+    index
+}
+
+fn handle_client(mut stream: TcpStream, state: Arc<ServerState>) {
+    // Copied out so the lock isn't held while waiting on the client
+    let (map_data, map_hash, map_generation) = {
+        let map = state.map.lock().unwrap();
+        (map.data.clone(), map.hash.clone(), map.generation)
+    };
+    let map_packet_size = map_data.len();
     println!("Map size: {}", map_packet_size);
     let size_bytes = (map_packet_size as u32).to_be_bytes();
 
@@ -63,14 +276,33 @@ pub fn handle_client(mut stream: TcpStream, clients: ClientList, map: Vec<u8>) {
     // Wait for client ack (optional)
     let mut tmp_buf = vec![0u8; 64];
     let read_res = stream.read(&mut tmp_buf);
-    if let Ok(n) = read_res {
-        if n > 0 {
-            println!("Client says: {}", String::from_utf8_lossy(&tmp_buf[..n]));
-        }
+    if let Ok(n) = read_res
+        && n > 0
+    {
+        println!("Client says: {}", String::from_utf8_lossy(&tmp_buf[..n]));
     }
 
     // Send the serialized map bytes
-    stream.write_all(&map).unwrap();
+    stream.write_all(&map_data).unwrap();
+
+    let sender_addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Could not get sender address: {}", e);
+            return;
+        }
+    };
+
+    let mut player_id: Option<u64> = None;
+    let mut player_name: Option<String> = None;
+    let mut moves = MoveValidator::default();
+    let mut shots = ShotValidator::default();
+    // Laps are timed here over the accepted positions, the client's own times are ignored
+    let mut race = RaceTimer::default();
+    let mut last_position: Option<Vec2> = None;
+    let mut last_move = Instant::now();
+    // Rotation step the validator's last position is from
+    let mut moves_generation = map_generation;
     let mut frame_counter = 0;
     loop {
         frame_counter += 1;
@@ -83,12 +315,14 @@ pub fn handle_client(mut stream: TcpStream, clients: ClientList, map: Vec<u8>) {
                 break;
             }
         };
-        if let Ok(clients_guard) = clients.lock() {
-            log(
-            frame_counter,
-            3600,
-            format!("Recieving packages correctly from {}", packet.id).as_str(),
-            );
+        if let Ok(clients_guard) = state.clients.lock() {
+            if let Packet::Player(ref player_packet) = packet {
+                log(
+                frame_counter,
+                3600,
+                format!("Recieving packages correctly from {}", player_packet.id).as_str(),
+                );
+            }
             log(
             frame_counter,
             10000,
@@ -98,54 +332,371 @@ pub fn handle_client(mut stream: TcpStream, clients: ClientList, map: Vec<u8>) {
             eprintln!("Failed to lock clients mutex for logging.");
         }
 
+        let mut clients_lock = state.clients.lock().unwrap();
 
-        let sender_addr = match stream.peer_addr() {
-            Ok(addr) => addr,
-            Err(e) => {
-                eprintln!("Could not get sender address: {}", e);
-                break;
-            }
-        };
+        match packet {
+            Packet::Player(mut player_packet) => {
+                if player_id.is_none() {
+                    player_id = Some(player_packet.id);
+                    player_name = Some(player_packet.name.clone());
+                    join_match(&state, &mut clients_lock, sender_addr, &player_packet, &map_hash);
+                }
+                record_kills(&player_packet, &state, &mut clients_lock);
 
-        let mut clients_lock = clients.lock().unwrap();
-
-        clients_lock.retain(|client| {
-            match client.peer_addr() {
-                Ok(addr) => {
-                    if addr != sender_addr {
-                        // Broadcast to other clients
-                        match client.try_clone() {
-                            Ok(mut cloned_stream) => {
-                                if let Err(e) = packet::send_packet(&mut cloned_stream, &packet) {
-                                    eprintln!("Error broadcasting to {}: {}", addr, e);
-                                    return false; // Remove client on error
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to clone stream: {}", e);
-                                return false;
-                            }
-                        }
+                let dt = last_move.elapsed().as_secs_f32();
+                last_move = Instant::now();
+                let generation = moves_generation;
+                let position = check_movement(&player_packet, &state, &mut moves, &mut moves_generation, dt);
+                let respawned = player_packet.actions.iter().any(|action| matches!(action, ActionType::Respawned));
+                if moves_generation != generation || respawned {
+                    race = RaceTimer::default();
+                    last_position = None;
+                }
+                let from = last_position.unwrap_or(position);
+                last_position = Some(position);
+                record_laps(&mut player_packet, &state, &mut clients_lock, &mut race, from, position, dt);
+                if position != vec2(player_packet.x, player_packet.y) {
+                    (player_packet.x, player_packet.y) = (position.x, position.y);
+                    if let Err(e) = packet::send_packet(&mut stream, &Packet::SetPosition(position.x, position.y)) {
+                        eprintln!("Error correcting the position of {}: {}", sender_addr, e);
                     }
-                    true
                 }
-                Err(e) => {
-                    eprintln!("Failed to get client address: {}", e);
-                    false
+                // Shots that couldn't have been fired are neither relayed nor applied
+                shots.refill(dt);
+                player_packet.actions.retain(|action| match action {
+                    ActionType::Shot(shot) => shots.allow(shot, position),
+                    _ => true,
+                });
+                if let Some(client) = clients_lock
+                    .iter_mut()
+                    .find(|client| client.stream.peer_addr().is_ok_and(|a| a == sender_addr))
+                {
+                    client.position = Some(position);
+                    if player_packet.actions.iter().any(|action| matches!(action, ActionType::Shot(_))) {
+                        client.last_shot = Some(Instant::now());
+                    }
+                }
+                apply_item_changes(&mut player_packet, &state);
+
+                let mut game = state.game.lock().unwrap();
+                let events = game.player_moved(player_packet.id, position.x, position.y);
+                announce(&mut clients_lock, &game, events);
+                drop(game);
+                broadcast(&mut clients_lock, &Packet::Player(player_packet.clone()), Some(sender_addr));
+                // After relaying the shots, so everyone traces them against the tiles they hit
+                apply_shots(&player_packet, &state, &mut clients_lock);
+            }
+            Packet::LeaderboardRequest(n) => {
+                let map_hash = state.map.lock().unwrap().hash.clone();
+                let top = state.leaderboards.lock().unwrap().top(&map_hash, n as usize);
+                // Replies go out under the clients lock so they can't interleave with broadcasts
+                if let Err(e) = packet::send_packet(&mut stream, &Packet::Leaderboard(top)) {
+                    eprintln!("Error sending leaderboard to {}: {}", sender_addr, e);
+                }
+            }
+            Packet::GhostUpload(mut ghost) => {
+                let map_hash = state.map.lock().unwrap().hash.clone();
+                let record = state.leaderboards.lock().unwrap().top(&map_hash, 1).pop();
+                // Only kept if it is the uploader's own lap that set the record timed here
+                if let Some(record) = record
+                    && player_name.as_ref() == Some(&record.name)
+                    && ghost.name == record.name
+                    && (ghost.time - record.time).abs() <= GHOST_TIME_TOLERANCE
+                    && ghost.samples.len() <= Ghost::max_samples(ghost.time)
+                {
+                    ghost.time = record.time;
+                    println!("Storing record ghost of {} ({:.2}s)", ghost.name, ghost.time);
+                    ghost.save(RECORD_GHOSTS, &map_hash);
+                } else {
+                    eprintln!("Ignoring ghost of {} from {}", ghost.name, sender_addr);
+                }
+            }
+            Packet::GhostRequest => {
+                let ghost = Ghost::load(RECORD_GHOSTS, &state.map.lock().unwrap().hash);
+                if let Err(e) = packet::send_packet(&mut stream, &Packet::Ghost(ghost)) {
+                    eprintln!("Error sending ghost to {}: {}", sender_addr, e);
                 }
             }
-        });
+            Packet::Pong(sent) => {
+                let ping = (state.started.elapsed().as_millis() as u64).saturating_sub(sent) as u32;
+                if let Some(client) = clients_lock
+                    .iter_mut()
+                    .find(|client| client.stream.peer_addr().is_ok_and(|a| a == sender_addr))
+                {
+                    client.ping = Some(ping);
+                }
+            }
+            Packet::Leaderboard(_)
+            | Packet::ServerMessage(_)
+            | Packet::Ghost(_)
+            | Packet::MatchInfo(_)
+            | Packet::MatchEnd(_)
+            | Packet::Flags(_)
+            | Packet::Ping(_)
+            | Packet::Scoreboard(_)
+            | Packet::ChangeMap(_)
+            | Packet::SetPosition(..)
+            | Packet::Hurt(_)
+            | Packet::TileChanged(_)
+            | Packet::ItemsChanged(_) => {
+                eprintln!("Ignoring server-only packet from {}", sender_addr);
+            }
+        }
     }
 
     // Remove disconnected client from list
-    let mut clients_lock = clients.lock().unwrap();
-    clients_lock.retain(|client| match client.peer_addr() {
-        Ok(addr) => addr != stream.peer_addr().unwrap(),
+    let mut clients_lock = state.clients.lock().unwrap();
+    clients_lock.retain(|client| match client.stream.peer_addr() {
+        Ok(addr) => addr != sender_addr,
         Err(_) => false,
     });
+    if let Some(id) = player_id {
+        let mut game = state.game.lock().unwrap();
+        if let Some(moved) = game.leave(id) {
+            println!("Moved player {} to balance teams", moved);
+            send_match_info(&mut clients_lock, &game);
+        }
+        let flags = game.flags();
+        if !flags.is_empty() {
+            broadcast(&mut clients_lock, &Packet::Flags(flags), None);
+        }
+    }
 
     println!(
         "Client disconnected. Remaining clients: {}",
         clients_lock.len()
     );
 }
+
+/// Sends `packet` to every client except `except`, dropping the ones that can't be reached.
+/// Clients still in the map handshake are skipped so nothing gets written in the middle of it.
+fn broadcast(clients: &mut Vec<Client>, packet: &Packet, except: Option<SocketAddr>) {
+    clients.retain(|client| {
+        match client.stream.peer_addr() {
+            Ok(addr) => {
+                if Some(addr) != except && client.player_id.is_some() {
+                    match client.stream.try_clone() {
+                        Ok(mut cloned_stream) => {
+                            if let Err(e) = packet::send_packet(&mut cloned_stream, packet) {
+                                eprintln!("Error broadcasting to {}: {}", addr, e);
+                                return false; // Remove client on error
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to clone stream: {}", e);
+                            return false;
+                        }
+                    }
+                }
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to get client address: {}", e);
+                false
+            }
+        }
+    });
+}
+
+/// Sends every playing client the match rules along with their own team.
+fn send_match_info(clients: &mut [Client], game: &Match) {
+    for client in clients.iter_mut() {
+        if let Some(id) = client.player_id
+            && let Err(e) = packet::send_packet(&mut client.stream, &Packet::MatchInfo(game.info_for(id)))
+        {
+            eprintln!("Error sending match info to {}: {}", id, e);
+        }
+    }
+}
+
+/// Registers the player behind a connection's first packet and tells them their team.
+/// `map_hash` is the map they got in the handshake, replaced if the rotation moved on since.
+fn join_match(
+    state: &ServerState,
+    clients: &mut [Client],
+    addr: SocketAddr,
+    player_packet: &PlayerPacket,
+    map_hash: &str,
+) {
+    let mut game = state.game.lock().unwrap();
+    game.join(player_packet.id, &player_packet.name);
+    let map = state.map.lock().unwrap();
+
+    if let Some(client) = clients
+        .iter_mut()
+        .find(|client| client.stream.peer_addr().is_ok_and(|a| a == addr))
+    {
+        client.player_id = Some(player_packet.id);
+        let info = game.info_for(player_packet.id);
+        println!("{} joined the match ({:?})", player_packet.name, info.team);
+        if map.hash != map_hash
+            && let Err(e) = packet::send_packet(&mut client.stream, &Packet::ChangeMap(map.original.clone()))
+        {
+            eprintln!("Error sending map to {}: {}", addr, e);
+        }
+        // Broadcasts skipped the player until now, so they get every change made so far
+        if !map.changes.is_empty()
+            && let Err(e) = packet::send_packet(&mut client.stream, &Packet::TileChanged(map.changes.clone()))
+        {
+            eprintln!("Error sending tile changes to {}: {}", addr, e);
+        }
+        if !map.item_changes.is_empty()
+            && let Err(e) = packet::send_packet(&mut client.stream, &Packet::ItemsChanged(map.item_changes.clone()))
+        {
+            eprintln!("Error sending item changes to {}: {}", addr, e);
+        }
+        if let Err(e) = packet::send_packet(&mut client.stream, &Packet::MatchInfo(info))
+            .and_then(|_| packet::send_packet(&mut client.stream, &Packet::Flags(game.flags())))
+        {
+            eprintln!("Error sending match info to {}: {}", addr, e);
+        }
+    }
+}
+
+/// Counts deaths reported by a player packet and ends the match once a limit is reached.
+/// A kill is only credited to a player in the match who just fired a shot the server
+/// accepted, otherwise the death counts as a suicide.
+fn record_kills(player_packet: &PlayerPacket, state: &ServerState, clients: &mut Vec<Client>) {
+    let mut game = state.game.lock().unwrap();
+    for action in &player_packet.actions {
+        if let ActionType::Died(killer) = action {
+            let victim = player_packet.id;
+            let just_shot = clients.iter().any(|client| {
+                client.player_id == Some(*killer)
+                    && client.last_shot.is_some_and(|at| at.elapsed() <= KILL_SHOT_WINDOW)
+            });
+            let killer = if just_shot && game.players.iter().any(|p| p.id == *killer) {
+                *killer
+            } else {
+                if *killer != victim {
+                    eprintln!("Ignoring the kill of {} claimed for {}", victim, killer);
+                }
+                victim
+            };
+            let events = game.record_kill(killer, victim);
+            announce(clients, &game, events);
+        }
+    }
+    if let Some(result) = game.check_end() {
+        broadcast(clients, &Packet::MatchEnd(result), None);
+    }
+}
+
+/// Sends game mode announcements to everyone, along with where the flags are now.
+fn announce(clients: &mut Vec<Client>, game: &Match, events: Vec<String>) {
+    if events.is_empty() {
+        return;
+    }
+    broadcast(clients, &Packet::Flags(game.flags()), None);
+    for event in events {
+        println!("{}", event);
+        broadcast(clients, &Packet::ServerMessage(event), None);
+    }
+}
+
+/// Applies the damage the shots of a player packet deal to tiles, and sends the tiles they
+/// destroyed to everyone.
+fn apply_shots(player_packet: &PlayerPacket, state: &ServerState, clients: &mut Vec<Client>) {
+    let mut map = state.map.lock().unwrap();
+    let mut changes = Vec::new();
+    for action in &player_packet.actions {
+        if let ActionType::Shot(shot) = action {
+            changes.extend(shot.damage_tile(&mut map.map));
+        }
+    }
+    if changes.is_empty() {
+        return;
+    }
+    map.changes.extend_from_slice(&changes);
+    drop(map);
+    broadcast(clients, &Packet::TileChanged(changes), None);
+}
+
+/// Applies the items a player packet picks up and drops to the map, dropping the ones that
+/// don't fit it, e.g. an item someone else picked up first, so they aren't relayed either.
+fn apply_item_changes(player_packet: &mut PlayerPacket, state: &ServerState) {
+    let mut map = state.map.lock().unwrap();
+    player_packet.actions.retain(|action| {
+        let change = match action {
+            ActionType::PickUp(id) => ItemChange::PickedUp(*id),
+            ActionType::Drop(item) => ItemChange::Dropped(item.clone()),
+            _ => return true,
+        };
+        if !map.map.apply_item_change(&change) {
+            return false;
+        }
+        map.item_changes.push(change);
+        true
+    });
+}
+
+/// Checks the position reported by a player packet, `dt` seconds after the previous one,
+/// and returns where the player is allowed to be.
+fn check_movement(
+    player_packet: &PlayerPacket,
+    state: &ServerState,
+    moves: &mut MoveValidator,
+    moves_generation: &mut u64,
+    dt: f32,
+) -> Vec2 {
+    let map = state.map.lock().unwrap();
+    if map.generation != *moves_generation {
+        moves.reset();
+        *moves_generation = map.generation;
+    }
+    for action in &player_packet.actions {
+        match action {
+            ActionType::Died(_) => moves.died(),
+            ActionType::Respawned => moves.respawn(),
+            _ => {}
+        }
+    }
+    moves.check(&map.map, vec2(player_packet.x, player_packet.y), dt)
+}
+
+/// Deals the damage of the hazard tiles players stand on. Health is only kept by the
+/// clients, so this is all the server does: a client ignoring `Hurt` doesn't die from it.
+fn hurt_players(state: &ServerState, clients: &mut [Client]) {
+    let map = state.map.lock().unwrap();
+    for client in clients.iter_mut() {
+        let Some(position) = client.position else {
+            continue;
+        };
+        let damage = movement::hazard_damage(&map.map, position);
+        if damage > 0
+            && let Err(e) = packet::send_packet(&mut client.stream, &Packet::Hurt(damage))
+        {
+            eprintln!("Error sending hazard damage: {}", e);
+        }
+    }
+}
+
+/// Times the lap of a player moving from `from` to `to` in `dt` seconds, stores the laps
+/// they finish and announces new map records to everyone. The lap times the packet reports
+/// are replaced by the server's own.
+fn record_laps(
+    player_packet: &mut PlayerPacket,
+    state: &ServerState,
+    clients: &mut Vec<Client>,
+    race: &mut RaceTimer,
+    from: Vec2,
+    to: Vec2,
+    dt: f32,
+) {
+    player_packet.actions.retain(|action| !matches!(action, ActionType::LapFinished(_)));
+    let mut leaderboards = state.leaderboards.lock().unwrap();
+    let map = state.map.lock().unwrap();
+    let Some(time) = race.update(&map.map, from, to, dt) else {
+        return;
+    };
+    player_packet.actions.push(ActionType::LapFinished(time));
+    let is_record = leaderboards.submit(&map.hash, &player_packet.name, time);
+    leaderboards.save();
+    drop(map);
+
+    if is_record {
+        let message = format!("New record: {} with {:.2}s!", player_packet.name, time);
+        println!("{}", message);
+        broadcast(clients, &Packet::ServerMessage(message), None);
+    }
+}
@@ -0,0 +1,248 @@
+use bincode::{Decode, Encode};
+use macroquad::prelude::{Rect, Vec2};
+
+use crate::common::TILE_SIZE;
+use crate::item::{Weapon, WeaponKind};
+use crate::map::{Map, TileChange};
+use crate::movement::PLAYER_WIDTH;
+use crate::tileset::TileDef;
+
+/// How much further than the weapon's muzzle offset a shot may start from the shooter's
+/// accepted position, as it moves a little between packets.
+const MUZZLE_SLACK: f32 = PLAYER_WIDTH;
+/// Seconds of fire a player can save up, so shots bunched together by the network still
+/// count.
+const FIRE_BURST_TIME: f32 = 0.5;
+
+/// A fired shot as it is replicated: the weapon, the muzzle position and the final
+/// direction (spread already applied), so every peer traces exactly the same line.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Decode, Encode)]
+pub struct Shot {
+    pub weapon_kind: WeaponKind,
+    pub x: f32,
+    pub y: f32,
+    pub angle: f32,
+}
+
+impl Shot {
+    pub fn trace(&self, map: &Map) -> ShotTrace {
+        let weapon = Weapon::from_kind(&self.weapon_kind);
+        trace_shot(map, Vec2::new(self.x, self.y), Vec2::from_angle(self.angle), weapon.range)
+    }
+
+    /// Wears down the destructible tile that stops the shot. Returns it if it was destroyed.
+    pub fn damage_tile(&self, map: &mut Map) -> Option<TileChange> {
+        let weapon = Weapon::from_kind(&self.weapon_kind);
+        let trace = self.trace(map);
+        let (x, y) = trace.tile?;
+        map.damage_tile(x, y, weapon.damage_at(trace.distance))
+    }
+}
+
+/// Checks the shots a client reports on the server, so they can't start away from the
+/// shooter or come faster than the weapon fires.
+#[derive(Debug, Default)]
+pub struct ShotValidator {
+    /// Seconds of fire available, refilled over time.
+    budget: f32,
+}
+
+impl ShotValidator {
+    /// Makes up for the `dt` seconds since the previous packet.
+    pub fn refill(&mut self, dt: f32) {
+        self.budget = (self.budget + dt).min(FIRE_BURST_TIME);
+    }
+
+    /// Whether `shot` could have been fired by a player at `position`, using up the time
+    /// between two shots of its weapon if so.
+    pub fn allow(&mut self, shot: &Shot, position: Vec2) -> bool {
+        let weapon = Weapon::from_kind(&shot.weapon_kind);
+        let reach = Vec2::from(weapon.shotoffset).length() + MUZZLE_SLACK;
+        // Written so NaN positions are rejected too
+        let near = Vec2::new(shot.x, shot.y).distance(position) <= reach;
+        if !near || self.budget < weapon.firerate {
+            return false;
+        }
+        self.budget -= weapon.firerate;
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShotTrace {
+    pub from: Vec2,
+    pub to: Vec2,
+    pub distance: f32,
+    /// Tile that stopped the shot, if any.
+    pub tile: Option<(usize, usize)>,
+}
+
+/// Traces a shot from `from` along `dir` until it reaches `range` or the first tile that
+/// blocks bullets.
+pub fn trace_shot(map: &Map, from: Vec2, dir: Vec2, range: f32) -> ShotTrace {
+    trace_ray(map, from, dir, range, |tile| tile.blocks_bullets)
+}
+
+/// Whether nothing that blocks sight lies between two points.
+pub fn line_of_sight(map: &Map, from: Vec2, to: Vec2) -> bool {
+    let distance = from.distance(to);
+    trace_ray(map, from, to - from, distance, |tile| tile.blocks_sight).distance >= distance
+}
+
+/// Walks the tiles along a ray until it reaches `range` or a tile for which `blocks` is true.
+fn trace_ray(map: &Map, from: Vec2, dir: Vec2, range: f32, blocks: impl Fn(&TileDef) -> bool) -> ShotTrace {
+    let dir = dir.normalize_or_zero();
+    if dir == Vec2::ZERO {
+        return ShotTrace { from, to: from, distance: 0.0, tile: None };
+    }
+
+    let mut tx = (from.x / TILE_SIZE).floor() as i64;
+    let mut ty = (from.y / TILE_SIZE).floor() as i64;
+    let step_x = if dir.x > 0.0 { 1 } else { -1 };
+    let step_y = if dir.y > 0.0 { 1 } else { -1 };
+
+    // Distance along the ray to cross one whole tile on each axis
+    let delta_x = if dir.x != 0.0 { (TILE_SIZE / dir.x).abs() } else { f32::INFINITY };
+    let delta_y = if dir.y != 0.0 { (TILE_SIZE / dir.y).abs() } else { f32::INFINITY };
+
+    // Distance along the ray to the first tile boundary on each axis
+    let mut next_x = if dir.x > 0.0 {
+        ((tx + 1) as f32 * TILE_SIZE - from.x) / dir.x
+    } else if dir.x < 0.0 {
+        (tx as f32 * TILE_SIZE - from.x) / dir.x
+    } else {
+        f32::INFINITY
+    };
+    let mut next_y = if dir.y > 0.0 {
+        ((ty + 1) as f32 * TILE_SIZE - from.y) / dir.y
+    } else if dir.y < 0.0 {
+        (ty as f32 * TILE_SIZE - from.y) / dir.y
+    } else {
+        f32::INFINITY
+    };
+
+    let mut distance = 0.0;
+    while distance < range {
+        if map.tile_defs(tx, ty).any(&blocks) {
+            return ShotTrace { from, to: from + dir * distance, distance, tile: Some((tx as usize, ty as usize)) };
+        }
+        if next_x < next_y {
+            distance = next_x;
+            next_x += delta_x;
+            tx += step_x;
+        } else {
+            distance = next_y;
+            next_y += delta_y;
+            ty += step_y;
+        }
+    }
+
+    ShotTrace { from, to: from + dir * range, distance: range, tile: None }
+}
+
+/// Distance along the traced shot at which it enters `rect`, if it does at all.
+pub fn hit_distance(trace: &ShotTrace, rect: &Rect) -> Option<f32> {
+    let delta = trace.to - trace.from;
+    let mut t_min: f32 = 0.0;
+    let mut t_max: f32 = 1.0;
+
+    for (origin, d, min, max) in [
+        (trace.from.x, delta.x, rect.x, rect.x + rect.w),
+        (trace.from.y, delta.y, rect.y, rect.y + rect.h),
+    ] {
+        if d == 0.0 {
+            if origin < min || origin > max {
+                return None;
+            }
+        } else {
+            let t1 = (min - origin) / d;
+            let t2 = (max - origin) / d;
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+            if t_min > t_max {
+                return None;
+            }
+        }
+    }
+
+    Some(t_min * trace.distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{LayerKind, Tile};
+    use crate::tileset::Tileset;
+
+    const ROCK: Tile = Tile { id: 2, variant: 0, rotation: 0 };
+
+    fn shot(x: f32, y: f32) -> Shot {
+        Shot { weapon_kind: WeaponKind::Ak47, x, y, angle: 0.0 }
+    }
+
+    fn map() -> Map {
+        Map::new(8, 3, Tileset::from_json(include_str!("../res/tileset.json")).unwrap())
+    }
+
+    #[test]
+    fn shots_stop_at_the_first_blocking_tile() {
+        let mut map = map();
+        *map.layer_mut(LayerKind::Walls).get_mut(3, 1).unwrap() = ROCK;
+        *map.layer_mut(LayerKind::Walls).get_mut(5, 1).unwrap() = ROCK;
+
+        let from = Vec2::new(16.0, 48.0);
+        let trace = trace_shot(&map, from, Vec2::X, 1000.0);
+        assert_eq!(trace.tile, Some((3, 1)));
+        assert_eq!(trace.distance, 3.0 * TILE_SIZE - from.x);
+        assert_eq!(trace.to, Vec2::new(3.0 * TILE_SIZE, 48.0));
+    }
+
+    #[test]
+    fn shots_end_at_their_range_if_nothing_blocks_them() {
+        let trace = trace_shot(&map(), Vec2::new(16.0, 48.0), Vec2::X, 100.0);
+        assert_eq!(trace.tile, None);
+        assert_eq!((trace.distance, trace.to), (100.0, Vec2::new(116.0, 48.0)));
+
+        // Players are only hit along the traced part of the shot
+        assert_eq!(hit_distance(&trace, &Rect::new(50.0, 40.0, 16.0, 16.0)), Some(34.0));
+        assert_eq!(hit_distance(&trace, &Rect::new(150.0, 40.0, 16.0, 16.0)), None);
+        assert_eq!(hit_distance(&trace, &Rect::new(50.0, 80.0, 16.0, 16.0)), None);
+    }
+
+    #[test]
+    fn damage_falls_off_up_to_the_range() {
+        let weapon = Weapon::from_kind(&WeaponKind::Magnum);
+        assert_eq!(weapon.damage_at(0.0), weapon.damage);
+        let at_range = (weapon.damage as f32 * (1.0 - weapon.falloff)).round() as u32;
+        assert_eq!(weapon.damage_at(weapon.range), at_range);
+        assert!(at_range < weapon.damage);
+        assert_eq!(weapon.damage_at(weapon.range + 1.0), 0);
+    }
+
+    #[test]
+    fn shots_must_start_at_the_shooter() {
+        let mut shots = ShotValidator::default();
+        shots.refill(1.0);
+        let position = Vec2::new(500.0, 500.0);
+        assert!(shots.allow(&shot(520.0, 508.0), position));
+        assert!(!shots.allow(&shot(900.0, 500.0), position));
+        assert!(!shots.allow(&shot(f32::NAN, 500.0), position));
+    }
+
+    #[test]
+    fn shots_are_limited_to_the_fire_rate() {
+        let firerate = Weapon::from_kind(&WeaponKind::Ak47).firerate;
+        let mut shots = ShotValidator::default();
+        let position = Vec2::ZERO;
+        assert!(!shots.allow(&shot(0.0, 0.0), position));
+
+        // A long pause only saves up a short burst
+        shots.refill(60.0);
+        let burst = (0..100).filter(|_| shots.allow(&shot(0.0, 0.0), position)).count();
+        assert_eq!(burst, (FIRE_BURST_TIME / firerate).round() as usize);
+
+        shots.refill(firerate);
+        assert!(shots.allow(&shot(0.0, 0.0), position));
+        assert!(!shots.allow(&shot(0.0, 0.0), position));
+    }
+}
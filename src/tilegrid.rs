@@ -0,0 +1,212 @@
+use std::ops::Range;
+
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::map::Tile;
+
+/// Most tiles a grid can have, 4096 by 4096, so a bad map file or packet can't make us
+/// allocate without bound.
+pub const MAX_TILES: u64 = 4096 * 4096;
+
+/// Tiles of a map stored row by row in a single vector.
+/// Serialized as runs of identical tiles, so large empty areas stay small on disk and on the wire.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(into = "EncodedGrid", try_from = "EncodedGrid")]
+pub struct TileGrid {
+    width: u32,
+    height: u32,
+    tiles: Vec<Tile>,
+}
+
+/// `count` tiles in a row equal to `tile`, continuing on the next rows if needed.
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct TileRun(pub u32, pub Tile);
+
+#[derive(Serialize, Deserialize, Encode, Decode)]
+struct EncodedGrid {
+    width: u32,
+    height: u32,
+    runs: Vec<TileRun>,
+}
+
+impl TileGrid {
+    pub fn new(width: u32, height: u32, fill: Tile) -> Self {
+        Self { width, height, tiles: vec![fill; width as usize * height as usize] }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width as usize && y < self.height as usize).then(|| y * self.width as usize + x)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&Tile> {
+        self.index(x, y).map(|i| &self.tiles[i])
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut Tile> {
+        self.index(x, y).map(|i| &mut self.tiles[i])
+    }
+
+    /// Tiles within the given columns and rows, clamped to the grid, row by row.
+    pub fn region(&self, xs: Range<usize>, ys: Range<usize>) -> impl Iterator<Item = (usize, usize, &Tile)> {
+        let width = self.width as usize;
+        let xs = xs.start.min(width)..xs.end.min(width);
+        let ys = ys.start.min(self.height as usize)..ys.end.min(self.height as usize);
+        ys.flat_map(move |y| {
+            let row = &self.tiles[y * width..(y + 1) * width];
+            xs.clone().map(move |x| (x, y, &row[x]))
+        })
+    }
+
+    /// Grows (positive) or crops (negative) the grid by the given number of tiles on each
+    /// side, filling new tiles with `fill`. Returns false, leaving the grid untouched, if it
+    /// would end up empty or over `MAX_TILES`.
+    pub fn resize(&mut self, left: i32, top: i32, right: i32, bottom: i32, fill: Tile) -> bool {
+        let width = self.width as i64 + left as i64 + right as i64;
+        let height = self.height as i64 + top as i64 + bottom as i64;
+        if width <= 0 || height <= 0 || width as u64 * height as u64 > MAX_TILES {
+            return false;
+        }
+        let mut resized = TileGrid::new(width as u32, height as u32, fill);
+        for y in 0..height {
+            for x in 0..width {
+                let (old_x, old_y) = (x - left as i64, y - top as i64);
+                if old_x >= 0
+                    && old_y >= 0
+                    && let Some(tile) = self.get(old_x as usize, old_y as usize)
+                {
+                    resized.tiles[(y * width + x) as usize] = *tile;
+                }
+            }
+        }
+        *self = resized;
+        true
+    }
+
+    pub fn runs(&self) -> Vec<TileRun> {
+        let mut runs: Vec<TileRun> = Vec::new();
+        for tile in &self.tiles {
+            match runs.last_mut() {
+                Some(TileRun(count, last)) if last == tile => *count += 1,
+                _ => runs.push(TileRun(1, *tile)),
+            }
+        }
+        runs
+    }
+
+    /// Checks the size and the run counts before allocating anything, as they come from
+    /// files and packets.
+    pub fn from_runs(width: u32, height: u32, runs: &[TileRun]) -> Result<Self, String> {
+        let size = width as u64 * height as u64;
+        if size > MAX_TILES {
+            return Err(format!("a {}x{} grid is over the limit of {} tiles", width, height, MAX_TILES));
+        }
+        let count: u64 = runs.iter().map(|TileRun(count, _)| *count as u64).sum();
+        if count != size {
+            return Err(format!("{} tiles don't fill a {}x{} grid", count, width, height));
+        }
+        let mut tiles = Vec::with_capacity(size as usize);
+        for TileRun(count, tile) in runs {
+            tiles.extend(std::iter::repeat_n(*tile, *count as usize));
+        }
+        Ok(Self { width, height, tiles })
+    }
+}
+
+impl From<TileGrid> for EncodedGrid {
+    fn from(grid: TileGrid) -> Self {
+        Self { width: grid.width, height: grid.height, runs: grid.runs() }
+    }
+}
+
+impl TryFrom<EncodedGrid> for TileGrid {
+    type Error = String;
+
+    fn try_from(encoded: EncodedGrid) -> Result<Self, String> {
+        TileGrid::from_runs(encoded.width, encoded.height, &encoded.runs)
+    }
+}
+
+impl Encode for TileGrid {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        EncodedGrid { width: self.width, height: self.height, runs: self.runs() }.encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for TileGrid {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let encoded = EncodedGrid::decode(decoder)?;
+        TileGrid::try_from(encoded).map_err(DecodeError::OtherString)
+    }
+}
+
+bincode::impl_borrow_decode!(TileGrid);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::EMPTY_TILE;
+
+    const ROCK: Tile = Tile { id: 2, variant: 0, rotation: 0 };
+
+    #[test]
+    fn region_is_clamped_to_the_grid() {
+        let mut grid = TileGrid::new(4, 3, EMPTY_TILE);
+        *grid.get_mut(3, 2).unwrap() = ROCK;
+        let region: Vec<(usize, usize)> = grid.region(2..10, 1..10).map(|(x, y, _)| (x, y)).collect();
+        assert_eq!(region, [(2, 1), (3, 1), (2, 2), (3, 2)]);
+        assert_eq!(grid.get(4, 0), None);
+    }
+
+    #[test]
+    fn resize_keeps_tiles_in_place() {
+        let mut grid = TileGrid::new(3, 3, EMPTY_TILE);
+        *grid.get_mut(1, 1).unwrap() = ROCK;
+
+        assert!(grid.resize(2, 0, -1, 1, EMPTY_TILE));
+        assert_eq!((grid.width(), grid.height()), (4, 4));
+        assert_eq!(grid.get(3, 1), Some(&ROCK));
+        assert_eq!(grid.get(1, 1), Some(&EMPTY_TILE));
+
+        assert!(grid.resize(-3, -1, 0, 0, EMPTY_TILE));
+        assert_eq!((grid.width(), grid.height()), (1, 3));
+        assert_eq!(grid.get(0, 0), Some(&ROCK));
+
+        assert!(!grid.resize(-1, 0, 0, 0, EMPTY_TILE));
+        assert_eq!((grid.width(), grid.height()), (1, 3));
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        let mut grid = TileGrid::new(5, 5, EMPTY_TILE);
+        *grid.get_mut(0, 1).unwrap() = ROCK;
+        *grid.get_mut(1, 1).unwrap() = ROCK;
+        let runs = grid.runs();
+        assert_eq!(runs.len(), 3);
+        assert_eq!(TileGrid::from_runs(5, 5, &runs), Ok(grid));
+        assert!(TileGrid::from_runs(5, 4, &runs).is_err());
+    }
+
+    #[test]
+    fn oversized_grids_are_rejected_before_allocating() {
+        let huge = [TileRun(u32::MAX, EMPTY_TILE), TileRun(u32::MAX, EMPTY_TILE)];
+        assert!(TileGrid::from_runs(u32::MAX, u32::MAX, &huge).is_err());
+        assert!(TileGrid::from_runs(4, 4, &huge).is_err());
+        assert!(TileGrid::from_runs(4097, 4096, &[TileRun(4097 * 4096, EMPTY_TILE)]).is_err());
+
+        let mut grid = TileGrid::new(4096, 1, EMPTY_TILE);
+        assert!(!grid.resize(0, 0, 0, 4096, EMPTY_TILE));
+        assert_eq!(grid.height(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+use std::f32::consts::FRAC_PI_2;
+use std::fs;
+
+use bincode::{Decode, Encode};
+use macroquad::math::{Rect, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::common::TILE_SIZE;
+
+/// Tile types available to maps, with the textures they are drawn with.
+pub const TILESET_FILE: &str = "res/tileset.json";
+
+/// Drawn for tile ids missing from the tileset: invisible and walkable.
+static UNKNOWN_TILE: TileDef = TileDef {
+    id: u16::MAX,
+    name: String::new(),
+    textures: Vec::new(),
+    collision: CollisionShape::None,
+    speed: 1.0,
+    grip: 1.0,
+    damage: 0,
+    one_way: false,
+    durability: None,
+    destroyed_into: 0,
+    blocks_bullets: false,
+    blocks_sight: false,
+};
+
+/// Part of a tile players can't walk through.
+#[derive(Serialize, Deserialize, Clone, Copy, Encode, Decode, Debug, Default, PartialEq)]
+pub enum CollisionShape {
+    #[default]
+    None,
+    Full,
+    /// Area of the unrotated tile, as fractions of its size.
+    Rect { x: f32, y: f32, w: f32, h: f32 },
+}
+
+impl CollisionShape {
+    /// World-space area the shape covers on the tile at (tx, ty) turned by `rotation`
+    /// quarter turns clockwise.
+    pub fn rect(&self, tx: usize, ty: usize, rotation: u8) -> Option<Rect> {
+        let (x, y, w, h) = match *self {
+            CollisionShape::None => return None,
+            CollisionShape::Full => (0.0, 0.0, 1.0, 1.0),
+            CollisionShape::Rect { x, y, w, h } => (x, y, w, h),
+        };
+        let center = Vec2::splat(0.5);
+        let turn = Vec2::from_angle((rotation % 4) as f32 * FRAC_PI_2);
+        let a = center + turn.rotate(Vec2::new(x, y) - center);
+        let b = center + turn.rotate(Vec2::new(x + w, y + h) - center);
+        let min = a.min(b);
+        let size = (a - b).abs();
+        Some(Rect::new(
+            (tx as f32 + min.x) * TILE_SIZE,
+            (ty as f32 + min.y) * TILE_SIZE,
+            size.x * TILE_SIZE,
+            size.y * TILE_SIZE,
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, PartialEq)]
+pub struct TileDef {
+    /// What map tiles store to refer to this type.
+    pub id: u16,
+    pub name: String,
+    /// One texture per variant. Tiles without textures aren't drawn.
+    #[serde(default)]
+    pub textures: Vec<String>,
+    #[serde(default)]
+    pub collision: CollisionShape,
+    /// Multiplier applied to the speed of players walking on the tile.
+    #[serde(default = "default_one")]
+    pub speed: f32,
+    /// Share of the difference between their velocity and the one they want that players
+    /// make up every 60th of a second. Below 1 they keep sliding, like on ice.
+    #[serde(default = "default_one")]
+    pub grip: f32,
+    /// Health lost every second by players standing on the tile.
+    #[serde(default)]
+    pub damage: u32,
+    /// The collision shape only stops players moving against the direction the tile points
+    /// in: right when unrotated, turning clockwise with its rotation.
+    #[serde(default)]
+    pub one_way: bool,
+    /// Damage the tile takes from shots before it is destroyed. Indestructible if not set.
+    #[serde(default)]
+    pub durability: Option<u32>,
+    /// Id of the tile left behind once destroyed.
+    #[serde(default)]
+    pub destroyed_into: u16,
+    #[serde(default)]
+    pub blocks_bullets: bool,
+    /// Players behind the tile are hidden.
+    #[serde(default)]
+    pub blocks_sight: bool,
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+/// The tile types a map's tiles refer to. The server's tileset travels with the map so
+/// every peer collides and traces shots against the same tiles.
+#[derive(Serialize, Deserialize, Clone, Encode, Decode, Debug, Default, PartialEq)]
+pub struct Tileset {
+    pub tiles: Vec<TileDef>,
+}
+
+impl Tileset {
+    pub fn load() -> Result<Tileset, String> {
+        let data = fs::read_to_string(TILESET_FILE).map_err(|e| format!("{}: {}", TILESET_FILE, e))?;
+        Self::from_json(&data).map_err(|e| format!("{}: {}", TILESET_FILE, e))
+    }
+
+    pub fn from_json(data: &str) -> Result<Tileset, String> {
+        let tileset: Tileset = serde_json::from_str(data).map_err(|e| e.to_string())?;
+        for (i, tile) in tileset.tiles.iter().enumerate() {
+            if tileset.tiles[..i].iter().any(|other| other.id == tile.id) {
+                return Err(format!("tile id {} is used more than once", tile.id));
+            }
+        }
+        Ok(tileset)
+    }
+
+    pub fn get(&self, id: u16) -> &TileDef {
+        self.tiles.iter().find(|tile| tile.id == id).unwrap_or(&UNKNOWN_TILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_tileset_loads() {
+        let tileset = Tileset::from_json(include_str!("../res/tileset.json")).unwrap();
+        assert!(tileset.get(2).blocks_bullets);
+        assert_eq!(tileset.get(0).collision, CollisionShape::None);
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let tileset = Tileset::from_json(r#"{"tiles":[{"id":7,"name":"Mud","speed":0.5}]}"#).unwrap();
+        let mud = tileset.get(7);
+        assert_eq!((mud.speed, mud.collision, mud.blocks_sight), (0.5, CollisionShape::None, false));
+        assert_eq!((mud.grip, mud.damage, mud.one_way, mud.durability), (1.0, 0, false, None));
+        assert_eq!(tileset.get(8), &UNKNOWN_TILE);
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        assert!(Tileset::from_json(r#"{"tiles":[{"id":1,"name":"A"},{"id":1,"name":"B"}]}"#).is_err());
+    }
+
+    #[test]
+    fn collision_rect_follows_rotation() {
+        // Top half of the tile, turned a quarter clockwise, becomes its right half
+        let half = CollisionShape::Rect { x: 0.0, y: 0.0, w: 1.0, h: 0.5 };
+        let rect = half.rect(1, 0, 1).unwrap();
+        let expected = Rect::new(1.5 * TILE_SIZE, 0.0, 0.5 * TILE_SIZE, TILE_SIZE);
+        assert!((rect.x - expected.x).abs() < 1e-3 && (rect.y - expected.y).abs() < 1e-3);
+        assert!((rect.w - expected.w).abs() < 1e-3 && (rect.h - expected.h).abs() < 1e-3);
+        assert_eq!(CollisionShape::None.rect(0, 0, 0), None);
+    }
+}